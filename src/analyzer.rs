@@ -1,26 +1,88 @@
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
 use super::bucketer::Bucketer;
 use super::frequency_sensor::{
-    Features, FrequencySensor, FrequencySensorParams, State as FrequencySensorState,
+    Features, FeaturesF32, FrequencySensor, FrequencySensorParams, State as FrequencySensorState,
+    Summary,
 };
 use super::sfft::SlidingFFT;
+use crate::filter::FilterParams;
 use crate::gain_control::{BoostController, BoostState, Params as GainControllerParams};
+use crate::types::{Frame, Spectrum};
+use crate::util::{lerp, lerp_usize, pick};
 
 pub struct Analyzer {
     boost: BoostController,
     sfft: SlidingFFT,
     bucketer: Bucketer,
     frequency_sensor: FrequencySensor,
+    beat: BeatDetector,
 
     block_size: usize,
     sample_count: usize,
+
+    // bucketed spectra accumulated since features were last emitted
+    pending: Vec<f64>,
+    pending_count: usize,
+    emit_sample_count: usize,
+
+    // peak input level of the current block, and the number of silent blocks in a row
+    block_peak: f64,
+    silent_blocks: usize,
+    // set when the silence timeout is reached, until the energy is reset on the next emit
+    silence_reset_pending: bool,
+
+    raw: Vec<f64>,
+    // the most recent bucketed spectrum fed to the frequency sensor
+    spectrum: Vec<f64>,
+
+    // reused by process_samples to hold the converted input
+    samples: Frame,
 }
 
-#[derive(Debug, Serialize, Deserialize, Copy, Clone)]
+/// OutputOrder determines the bucket ordering of the `Features` returned by `Analyzer::process`.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Default, PartialEq)]
+pub enum OutputOrder {
+    #[default]
+    LowToHigh,
+    HighToLow,
+}
+
+/// Fields missing from serialized params take their value from `AnalyzerParams::default`.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq)]
+#[serde(default)]
 pub struct AnalyzerParams {
     pub boost: GainControllerParams,
+    /// boost_attack smooths the gain applied by the boost while it is rising.
+    pub boost_attack: FilterParams,
+    /// boost_release smooths the gain applied by the boost while it is falling.
+    pub boost_release: FilterParams,
     pub fs: FrequencySensorParams,
+    pub output_order: OutputOrder,
+    /// beat_decay is the factor the beat pulse decays by on each block without an onset.
+    pub beat_decay: f64,
+    /// beat_max_decay is the factor the running maximum onset strength, which normalizes the
+    /// beat pulse, decays by on each block.
+    pub beat_max_decay: f64,
+    /// min_emit_interval_samples is the minimum number of samples between emitted features.
+    /// Spectra from blocks completed in between are averaged into the next emitted features.
+    pub min_emit_interval_samples: usize,
+    /// silence_threshold is the peak sample level below which a block counts as silent.
+    pub silence_threshold: f64,
+    /// silence_timeout_blocks is the number of consecutive silent blocks after which the
+    /// accumulated energy is reset, once, so that resuming audio starts clean. The value scales
+    /// are kept, so the first blocks after the silence are scaled as before it. Zero disables
+    /// the reset.
+    pub silence_timeout_blocks: usize,
+    /// raw_compression is the exponent applied to the bucketed spectrum returned by
+    /// `Analyzer::process_raw`. Values below 1 compress the dynamic range; 1 leaves it as is.
+    pub raw_compression: f64,
+    /// warmup_passes is the number of times the first emitted spectrum is run through the
+    /// frequency sensor before its features are emitted, so that its filters start near their
+    /// steady state rather than ramping up from zero. The passes count toward the frame count
+    /// of the features.
+    pub warmup_passes: usize,
 }
 
 #[derive(Debug, Serialize, Default, Clone)]
@@ -33,12 +95,133 @@ impl Default for AnalyzerParams {
     fn default() -> Self {
         Self {
             boost: Default::default(),
+            boost_attack: Default::default(),
+            boost_release: Default::default(),
             fs: Default::default(),
+            output_order: Default::default(),
+            beat_decay: 0.85,
+            beat_max_decay: 0.999,
+            min_emit_interval_samples: 0,
+            silence_threshold: 1e-4,
+            silence_timeout_blocks: 0,
+            raw_compression: 1.,
+            warmup_passes: 0,
+        }
+    }
+}
+
+impl AnalyzerParams {
+    /// lerp blends two sets of params, for a smooth transition between presets. Numeric fields,
+    /// including the tau and gain of every `FilterParams`, are interpolated linearly; counts are
+    /// rounded, and modes and flags switch from `a` to `b` halfway.
+    pub fn lerp(a: &Self, b: &Self, t: f64) -> Self {
+        Self {
+            boost: GainControllerParams::lerp(&a.boost, &b.boost, t),
+            boost_attack: FilterParams::lerp(&a.boost_attack, &b.boost_attack, t),
+            boost_release: FilterParams::lerp(&a.boost_release, &b.boost_release, t),
+            fs: FrequencySensorParams::lerp(&a.fs, &b.fs, t),
+            output_order: pick(a.output_order, b.output_order, t),
+            beat_decay: lerp(a.beat_decay, b.beat_decay, t),
+            beat_max_decay: lerp(a.beat_max_decay, b.beat_max_decay, t),
+            min_emit_interval_samples: lerp_usize(
+                a.min_emit_interval_samples,
+                b.min_emit_interval_samples,
+                t,
+            ),
+            silence_threshold: lerp(a.silence_threshold, b.silence_threshold, t),
+            silence_timeout_blocks: lerp_usize(
+                a.silence_timeout_blocks,
+                b.silence_timeout_blocks,
+                t,
+            ),
+            raw_compression: lerp(a.raw_compression, b.raw_compression, t),
+            warmup_passes: lerp_usize(a.warmup_passes, b.warmup_passes, t),
         }
     }
+
+    /// validate returns an error if any of the params would make the analyzer unstable.
+    pub fn validate(&self) -> Result<()> {
+        self.boost.validate()?;
+        self.fs.validate()
+    }
+
+    /// to_json serializes the params, such as to save them as a preset. Each `FilterParams` is
+    /// stored as its tau and gain, from which its coefficients are recomputed on load.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// from_json deserializes params written by `to_json`. Missing fields take their default.
+    #[cfg(feature = "json")]
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+}
+
+// sense runs the bucketed spectrum through the beat detector and the frequency sensor, warming
+// the frequency sensor up on its first spectrum. A copy of the spectrum is kept in `spectrum`.
+fn sense(
+    beat: &mut BeatDetector,
+    frequency_sensor: &mut FrequencySensor,
+    spectrum: &mut [f64],
+    bins: &mut Vec<f64>,
+    params: &AnalyzerParams,
+) {
+    for (s, b) in spectrum.iter_mut().zip(bins.iter()) {
+        *s = *b;
+    }
+    beat.process(bins, params);
+    if frequency_sensor.get_features().get_frame_count() == 0 {
+        for _ in 0..params.warmup_passes {
+            frequency_sensor.process(&mut bins.to_vec(), &params.fs);
+        }
+    }
+    frequency_sensor.process(bins, &params.fs);
+}
+
+/// BeatDetector tracks the half-wave rectified spectral flux of the bucketed spectrum as an
+/// onset envelope, normalized by its running maximum.
+struct BeatDetector {
+    prev: Vec<f64>,
+    flux_max: f64,
+    pulse: f64,
+    onset: bool,
+}
+
+impl BeatDetector {
+    fn new(size: usize) -> Self {
+        Self {
+            prev: vec![0f64; size],
+            flux_max: 0.,
+            pulse: 0.,
+            onset: false,
+        }
+    }
+
+    fn process(&mut self, bins: &[f64], params: &AnalyzerParams) {
+        let flux: f64 = bins
+            .iter()
+            .zip(self.prev.iter())
+            .map(|(x, p)| (x - p).max(0.))
+            .sum();
+        self.prev.copy_from_slice(bins);
+
+        self.flux_max = (self.flux_max * params.beat_max_decay).max(flux);
+        let onset = if self.flux_max > 0. {
+            flux / self.flux_max
+        } else {
+            0.
+        };
+        let decayed = self.pulse * params.beat_decay;
+        self.onset = onset > decayed;
+        self.pulse = decayed.max(onset);
+    }
 }
 
 impl Analyzer {
+    /// new creates an analyzer whose buckets span from 32Hz to 22kHz, assuming 22kHz is the
+    /// Nyquist frequency, i.e. a sample rate of 44kHz. See `with_sample_rate` for other rates.
     pub fn new(fft_size: usize, block_size: usize, size: usize, length: usize) -> Analyzer {
         let boost = BoostController::new();
         let sfft = SlidingFFT::new(fft_size);
@@ -49,29 +232,320 @@ impl Analyzer {
             sfft,
             bucketer,
             frequency_sensor,
+            beat: BeatDetector::new(size),
             block_size,
             sample_count: 0,
+            pending: vec![0f64; size],
+            pending_count: 0,
+            emit_sample_count: 0,
+            block_peak: 0.,
+            silent_blocks: 0,
+            silence_reset_pending: false,
+            raw: vec![0f64; size],
+            spectrum: vec![0f64; size],
+            samples: Frame::default(),
+        }
+    }
+
+    /// with_sample_rate creates an analyzer for input at `sample_rate` whose buckets span from
+    /// `f_min` to `f_max`, which is clamped to the Nyquist frequency.
+    pub fn with_sample_rate(
+        fft_size: usize,
+        block_size: usize,
+        size: usize,
+        length: usize,
+        sample_rate: f64,
+        f_min: f64,
+        f_max: f64,
+    ) -> Analyzer {
+        let mut analyzer = Analyzer::new(fft_size, block_size, size, length);
+        analyzer.bucketer = Bucketer::with_sample_rate(fft_size, size, f_min, f_max, sample_rate);
+        analyzer
+    }
+
+    /// reset clears all of the analyzer's state, including buffered input, so the next frame is
+    /// processed as if it were the first. The configured sizes are kept.
+    pub fn reset(&mut self) {
+        self.boost.reset();
+        self.sfft.reset();
+        self.frequency_sensor.reset();
+        self.beat = BeatDetector::new(self.pending.len());
+        self.sample_count = 0;
+        for x in self
+            .pending
+            .iter_mut()
+            .chain(self.raw.iter_mut())
+            .chain(self.spectrum.iter_mut())
+        {
+            *x = 0.;
+        }
+        self.pending_count = 0;
+        self.emit_sample_count = 0;
+        self.block_peak = 0.;
+        self.silent_blocks = 0;
+        self.silence_reset_pending = false;
+    }
+
+    /// config_for picks an `(fft_size, block_size)` for `Analyzer::new`. The fft size is the
+    /// smallest power of two whose bins are no wider than `freq_resolution_hz`, and the block
+    /// size emits features at about `target_fps`. A block only completes at the end of the
+    /// frame which fills it, so the frames passed to `process` should divide the block size.
+    ///
+    /// A finer frequency resolution needs a longer fft, which also smears each frame over a
+    /// longer stretch of time, so fast transients blur and the analysis lags further behind the
+    /// audio. A higher fps costs an fft for every block; when the block size exceeds the fft
+    /// size, the samples in between are not analyzed at all.
+    pub fn config_for(
+        sample_rate: f64,
+        target_fps: f64,
+        freq_resolution_hz: f64,
+    ) -> (usize, usize) {
+        if sample_rate <= 0. || target_fps <= 0. || freq_resolution_hz <= 0. {
+            panic!("sample rate, fps and frequency resolution must be positive");
+        }
+        let fft_size = ((sample_rate / freq_resolution_hz).ceil() as usize).next_power_of_two();
+        let block_size = ((sample_rate / target_fps).round() as usize).max(1);
+        (fft_size, block_size)
+    }
+
+    /// process feeds a frame of samples through the analyzer, returning a copy of the features
+    /// in the order given by `params.output_order` whenever a new block completes. No features
+    /// are returned until a full fft_size of samples has been fed.
+    pub fn process(&mut self, frame: &mut Frame, params: &AnalyzerParams) -> Option<Features> {
+        if !self.update(frame, params) {
+            return None;
         }
+        Some(self.owned_features(params))
+    }
+
+    /// process_spectrum runs a precomputed magnitude spectrum, such as from an external FFT,
+    /// through the bucketer and frequency sensor, skipping the boost and the sliding FFT. The
+    /// spectrum must have the number of bins the analyzer's bucketer expects, `fft_size / 2`,
+    /// or `None` is returned. The block size, emit interval and silence timeout do not apply.
+    pub fn process_spectrum(
+        &mut self,
+        spectrum: &Spectrum,
+        params: &AnalyzerParams,
+    ) -> Option<Features> {
+        if spectrum.len() != self.bucketer.get_input_size() {
+            return None;
+        }
+        let bins = self.bucketer.bucket(spectrum);
+        sense(
+            &mut self.beat,
+            &mut self.frequency_sensor,
+            &mut self.spectrum,
+            bins,
+            params,
+        );
+        Some(self.owned_features(params))
+    }
+
+    /// process_samples is like `process`, but takes samples of any cpal sample type, such as
+    /// those handed to the callback of `Source::get_stream`. The samples are converted to f64
+    /// into a buffer that is reused between calls.
+    pub fn process_samples<T: cpal::Sample>(
+        &mut self,
+        samples: &[T],
+        params: &AnalyzerParams,
+    ) -> Option<Features> {
+        let mut frame = std::mem::take(&mut self.samples);
+        frame.clear();
+        frame.extend(samples.iter().map(|s| s.to_f32() as f64));
+        let features = self.process(&mut frame, params);
+        self.samples = frame;
+        features
+    }
+
+    /// process_in_place is the real-time path. It is like `process`, but returns a borrow of the
+    /// analyzer's own features rather than a copy, and makes no heap allocations once the
+    /// analyzer has emitted its first features. The features are always ordered from low to
+    /// high frequency, regardless of `params.output_order`.
+    pub fn process_in_place(
+        &mut self,
+        frame: &mut [f64],
+        params: &AnalyzerParams,
+    ) -> Option<&Features> {
+        if !self.update(frame, params) {
+            return None;
+        }
+        Some(self.frequency_sensor.get_features())
+    }
+
+    /// process_ref is like `process_in_place`, but only returns whether a new block completed.
+    /// The features are then read by reference through `get_features`, which borrows the
+    /// analyzer until they are released, so they must be read before the next frame is fed.
+    pub fn process_ref(&mut self, frame: &mut [f64], params: &AnalyzerParams) -> bool {
+        self.update(frame, params)
+    }
+
+    /// finish is for offline processing, such as of a file, where the input ends partway
+    /// through a block. It zero pads the partial block and returns its features, or `None` if
+    /// no samples have been fed since the last block completed.
+    pub fn finish(&mut self, params: &AnalyzerParams) -> Option<Features> {
+        if self.sample_count == 0 {
+            return None;
+        }
+        let mut padding = Frame::new(self.block_size - self.sample_count);
+        // the final block is emitted regardless of the minimum emit interval
+        self.emit_sample_count = self.emit_sample_count.max(params.min_emit_interval_samples);
+        self.process(&mut padding, params)
+    }
+
+    /// process_f32_out is like `process`, but writes the features as `f32` into `out` rather
+    /// than returning a copy. It returns whether `out` was updated.
+    pub fn process_f32_out(
+        &mut self,
+        frame: &mut Frame,
+        params: &AnalyzerParams,
+        out: &mut FeaturesF32,
+    ) -> bool {
+        if !self.update(frame, params) {
+            return false;
+        }
+        self.frequency_sensor.get_features().write_f32(out);
+        if params.output_order == OutputOrder::HighToLow {
+            out.reverse_buckets();
+        }
+        true
+    }
+
+    /// loop_point should be called when the input is about to jump, such as at the seam of a
+    /// looping clip. It cross-fades over `fade_len` samples on either side of the seam to
+    /// suppress the transient it would otherwise cause. See `SlidingFFT::loop_point`.
+    pub fn loop_point(&mut self, fade_len: usize) {
+        self.sfft.loop_point(fade_len);
     }
 
-    pub fn process(&mut self, frame: &mut Vec<f64>, params: &AnalyzerParams) -> Option<Features> {
+    /// process_raw is the minimum latency path. It returns the bucketed spectrum, compressed by
+    /// `params.raw_compression`, whenever a new block completes, skipping the boost, gain
+    /// control and all temporal filtering. It shares the FFT window with `process`, so an
+    /// analyzer should be fed through one or the other.
+    pub fn process_raw(&mut self, frame: &Frame, params: &AnalyzerParams) -> Option<&Vec<f64>> {
         self.sample_count += frame.len();
-        self.boost.process(frame, &params.boost);
         self.sfft.push_input(frame);
+        if self.sample_count < self.block_size {
+            return None;
+        }
+        self.sample_count = 0;
+        if !self.sfft.is_primed() {
+            return None;
+        }
+        let spectrum = self.sfft.process();
+        let bins = self.bucketer.bucket(spectrum);
+        for (r, b) in self.raw.iter_mut().zip(bins.iter()) {
+            *r = b.powf(params.raw_compression);
+        }
+        if params.output_order == OutputOrder::HighToLow {
+            self.raw.reverse();
+        }
+        Some(&self.raw)
+    }
+
+    // update runs the pipeline on the frame, returning whether the features were updated
+    fn update(&mut self, frame: &mut [f64], params: &AnalyzerParams) -> bool {
+        self.sample_count += frame.len();
+        self.emit_sample_count += frame.len();
+        self.block_peak = frame.iter().fold(self.block_peak, |m, x| m.max(x.abs()));
+        self.boost.process(
+            frame,
+            &params.boost,
+            &params.boost_attack,
+            &params.boost_release,
+        );
+        self.sfft.push_slice(frame);
         if self.sample_count >= self.block_size {
             self.sample_count = 0;
+            if self.block_peak < params.silence_threshold {
+                self.silent_blocks += 1;
+                if self.silent_blocks == params.silence_timeout_blocks {
+                    self.silence_reset_pending = true;
+                }
+            } else {
+                self.silent_blocks = 0;
+                self.silence_reset_pending = false;
+            }
+            self.block_peak = 0.;
+            if !self.sfft.is_primed() {
+                return false;
+            }
             let spectrum = self.sfft.process();
             let bins = self.bucketer.bucket(spectrum);
-            self.frequency_sensor.process(bins, &params.fs);
-            return Some(self.frequency_sensor.get_features().to_owned());
+
+            for (p, b) in self.pending.iter_mut().zip(bins.iter()) {
+                *p += b;
+            }
+            self.pending_count += 1;
+            if self.emit_sample_count < params.min_emit_interval_samples {
+                return false;
+            }
+            self.emit_sample_count = 0;
+            let n = self.pending_count as f64;
+            for (b, p) in bins.iter_mut().zip(self.pending.iter_mut()) {
+                *b = *p / n;
+                *p = 0.;
+            }
+            self.pending_count = 0;
+
+            sense(
+                &mut self.beat,
+                &mut self.frequency_sensor,
+                &mut self.spectrum,
+                bins,
+                params,
+            );
+            if self.silence_reset_pending {
+                self.silence_reset_pending = false;
+                self.frequency_sensor.reset_energy();
+            }
+            return true;
+        }
+        false
+    }
+
+    // owned_features returns a copy of the features in the order given by params.output_order
+    fn owned_features(&self, params: &AnalyzerParams) -> Features {
+        let mut features = self.frequency_sensor.get_features().to_owned();
+        if params.output_order == OutputOrder::HighToLow {
+            features.reverse_buckets();
         }
-        None
+        features
     }
 
+    /// last_spectrum returns the bucketed spectrum most recently fed to the frequency sensor,
+    /// before any of its preemphasis, gain control or filtering, ordered from low to high
+    /// frequency. Spectra averaged over the emit interval are returned as their average.
+    pub fn last_spectrum(&self) -> &Vec<f64> {
+        &self.spectrum
+    }
+
+    /// get_features returns the current features, always ordered from low to high frequency.
     pub fn get_features(&self) -> &Features {
         &self.frequency_sensor.get_features()
     }
 
+    /// set_metadata attaches per-bucket metadata, such as colors or weights, which is returned
+    /// unchanged alongside every set of features.
+    pub fn set_metadata(&mut self, metadata: Vec<f64>) -> Result<()> {
+        self.frequency_sensor.set_metadata(metadata)
+    }
+
+    /// beat_pulse returns a value in [0, 1] which jumps up on each onset, relative to the
+    /// strongest recent onset, and decays by `beat_decay` every block in between.
+    pub fn beat_pulse(&self) -> f64 {
+        self.beat.pulse
+    }
+
+    /// summary returns a compact summary of the current features for network sync, see
+    /// `Features::summary`. The beat flag is set when an onset lifted the beat pulse on the
+    /// latest block.
+    pub fn summary(&self, bands: usize) -> Summary {
+        let centers = self.bucketer.center_frequencies();
+        let mut summary = self.get_features().summary(bands, &centers);
+        summary.beat = self.beat.onset;
+        summary
+    }
+
     pub fn write_debug<W>(&self, w: &mut W) -> core::fmt::Result
     where
         W: core::fmt::Write,
@@ -82,6 +556,26 @@ impl Analyzer {
         writeln!(w, "}}")
     }
 
+    /// write_json writes the same data as `write_debug`, the analyzer state and the current
+    /// features, as a single JSON object.
+    #[cfg(feature = "json")]
+    pub fn write_json<W>(&self, w: &mut W) -> core::fmt::Result
+    where
+        W: core::fmt::Write,
+    {
+        #[derive(Serialize)]
+        struct Debug<'a> {
+            state: AnalyzerState,
+            features: &'a Features,
+        }
+        let json = serde_json::to_string(&Debug {
+            state: self.get_state(),
+            features: self.get_features(),
+        })
+        .map_err(|_| core::fmt::Error)?;
+        w.write_str(&json)
+    }
+
     pub fn get_state(&self) -> AnalyzerState {
         AnalyzerState {
             boost: self.boost.get_state(),
@@ -92,14 +586,21 @@ impl Analyzer {
 
 #[cfg(test)]
 mod tests {
-    use super::Analyzer;
+    use super::{
+        Analyzer, AnalyzerParams, Features, FeaturesF32, FrequencySensorParams,
+        GainControllerParams, OutputOrder,
+    };
+    use crate::bucketer::Bucketer;
+    use crate::filter::FilterParams;
+    use crate::types::{Frame, Spectrum};
+    use std::f64::consts::PI;
 
     #[test]
     fn it_works() {
         let mut a = Analyzer::new(128, 128, 16, 2);
 
         use std::f64::consts::PI;
-        let mut input: Vec<f64> = (0..128)
+        let mut input: Frame = (0..128)
             .map(|x| (x as f64 * 2. * PI / 128.).cos())
             .collect();
 
@@ -109,4 +610,593 @@ mod tests {
 
         println!("{:?}", a.get_features());
     }
+
+    #[test]
+    fn high_to_low_output_order() {
+        use std::f64::consts::PI;
+        let input: Frame = (0..128).map(|x| (x as f64 * 2. * PI / 16.).cos()).collect();
+
+        let low_to_high = AnalyzerParams::default();
+        let high_to_low = AnalyzerParams {
+            output_order: OutputOrder::HighToLow,
+            ..Default::default()
+        };
+
+        let mut a = Analyzer::new(128, 128, 16, 2);
+        let mut b = Analyzer::new(128, 128, 16, 2);
+        for _ in 0..16 {
+            let fa = a.process(&mut input.clone(), &low_to_high).unwrap();
+            let fb = b.process(&mut input.clone(), &high_to_low).unwrap();
+
+            let reversed = |v: &Vec<f64>| v.iter().rev().cloned().collect::<Vec<f64>>();
+            for i in 0..2 {
+                assert_eq!(&reversed(fa.get_amplitudes(i)), fb.get_amplitudes(i));
+            }
+            assert_eq!(&reversed(fa.get_scales()), fb.get_scales());
+            assert_eq!(&reversed(fa.get_diff()), fb.get_diff());
+            assert_eq!(&reversed(fa.get_energy()), fb.get_energy());
+        }
+    }
+
+    #[test]
+    fn beat_pulse() {
+        use std::f64::consts::PI;
+        // keep the boost from ringing so that the tone is steady after its onset
+        let params = AnalyzerParams {
+            boost: GainControllerParams {
+                kp: 0.005,
+                ki: 0.005,
+                kd: 0.,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut a = Analyzer::new(128, 128, 16, 2);
+
+        for _ in 0..8 {
+            a.process(&mut Frame::new(128), &params);
+            assert_eq!(a.beat_pulse(), 0.);
+        }
+
+        let tone: Frame = (0..128).map(|x| (x as f64 * 2. * PI / 16.).cos()).collect();
+        a.process(&mut tone.clone(), &params);
+        let mut last = a.beat_pulse();
+        assert_eq!(last, 1.);
+        assert!(a.summary(4).beat);
+
+        for _ in 0..8 {
+            a.process(&mut tone.clone(), &params);
+            let pulse = a.beat_pulse();
+            assert!(pulse < last, "{} >= {}", pulse, last);
+            assert!(!a.summary(4).beat);
+            last = pulse;
+        }
+    }
+
+    #[test]
+    fn waits_for_full_window() {
+        let mut a = Analyzer::new(128, 32, 16, 2);
+        for _ in 0..3 {
+            assert!(a
+                .process(&mut Frame::new(32), &Default::default())
+                .is_none());
+        }
+        assert!(a
+            .process(&mut Frame::new(32), &Default::default())
+            .is_some());
+        assert!(a
+            .process(&mut Frame::new(32), &Default::default())
+            .is_some());
+    }
+
+    #[test]
+    fn metadata_passthrough() {
+        let mut a = Analyzer::new(128, 128, 4, 2);
+        assert!(a.set_metadata(vec![1., 2.]).is_err());
+        a.set_metadata(vec![1., 2., 3., 4.]).unwrap();
+
+        let high_to_low = AnalyzerParams {
+            output_order: OutputOrder::HighToLow,
+            ..Default::default()
+        };
+        for _ in 0..4 {
+            let f = a
+                .process(&mut Frame::new(128), &Default::default())
+                .unwrap();
+            assert_eq!(f.get_metadata(), &vec![1., 2., 3., 4.]);
+            assert_eq!(f.get_metadata().len(), f.get_amplitudes(0).len());
+
+            let f = a.process(&mut Frame::new(128), &high_to_low).unwrap();
+            assert_eq!(f.get_metadata(), &vec![4., 3., 2., 1.]);
+        }
+        assert_eq!(a.get_features().get_metadata(), &vec![1., 2., 3., 4.]);
+    }
+
+    #[test]
+    fn min_emit_interval() {
+        let count_emitted = |params: &AnalyzerParams| {
+            let mut a = Analyzer::new(128, 128, 16, 2);
+            (0..64)
+                .filter_map(|_| a.process(&mut Frame::new(128), params))
+                .count()
+        };
+
+        assert_eq!(count_emitted(&Default::default()), 64);
+
+        let capped = AnalyzerParams {
+            min_emit_interval_samples: 512,
+            ..Default::default()
+        };
+        assert_eq!(count_emitted(&capped), 16);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn write_json() {
+        let mut a = Analyzer::new(128, 128, 16, 2);
+        let mut input: Frame = (0..128).map(|x| (x as f64 / 8.).sin()).collect();
+        for _ in 0..4 {
+            a.process(&mut input, &Default::default());
+        }
+
+        let mut out = String::new();
+        a.write_json(&mut out).unwrap();
+        let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(v["features"]["amplitudes"].as_array().unwrap().len(), 16);
+        assert!(v["state"]["fs"]["amp_filter"].is_array());
+    }
+
+    #[test]
+    fn silence_timeout() {
+        let params = AnalyzerParams {
+            silence_timeout_blocks: 8,
+            ..Default::default()
+        };
+        let mut a = Analyzer::new(128, 128, 16, 2);
+
+        use std::f64::consts::PI;
+        let tone: Frame = (0..128)
+            .map(|x| (x as f64 * 16. * PI / 128.).sin())
+            .collect();
+        for _ in 0..32 {
+            a.process(&mut tone.clone(), &params);
+        }
+
+        for _ in 0..7 {
+            let f = a.process(&mut Frame::new(128), &params).unwrap();
+            assert!(f.get_energy().iter().any(|&e| e != 0.));
+        }
+        let f = a.process(&mut Frame::new(128), &params).unwrap();
+        assert!(f.get_energy().iter().all(|&e| e == 0.));
+        // the filters and scales keep their state, only the energy is cleared
+        assert!(f.get_scales().iter().all(|&s| s != 0.));
+        assert!(f.get_amplitudes(0).iter().any(|&x| x != 0.));
+
+        // the energy is only reset once, when the timeout is reached
+        let f = a.process(&mut Frame::new(128), &params).unwrap();
+        assert!(f.get_energy().iter().any(|&e| e != 0.));
+    }
+
+    #[test]
+    fn silence_timeout_between_emits() {
+        // features are emitted every other block, and the timeout falls between two emits
+        let params = AnalyzerParams {
+            silence_timeout_blocks: 8,
+            min_emit_interval_samples: 256,
+            ..Default::default()
+        };
+        let mut a = Analyzer::new(128, 128, 16, 2);
+
+        let tone: Frame = (0..128)
+            .map(|x| (x as f64 * 16. * PI / 128.).sin())
+            .collect();
+        for _ in 0..33 {
+            a.process(&mut tone.clone(), &params);
+        }
+
+        let emitted: Vec<(usize, Features)> = (1..=12)
+            .filter_map(|k| a.process(&mut Frame::new(128), &params).map(|f| (k, f)))
+            .collect();
+        assert!(emitted.iter().all(|(k, _)| k % 2 == 1));
+        for (k, f) in emitted.iter() {
+            let reset = f.get_energy().iter().all(|&e| e == 0.);
+            assert_eq!(reset, *k == 9, "block {}", k);
+        }
+    }
+
+    #[test]
+    fn process_f32_out() {
+        let mut a = Analyzer::new(128, 128, 16, 2);
+        let mut b = Analyzer::new(128, 128, 16, 2);
+        let params = AnalyzerParams {
+            output_order: OutputOrder::HighToLow,
+            ..Default::default()
+        };
+        let input: Frame = (0..128).map(|x| (x as f64 / 4.).sin()).collect();
+
+        let mut out = FeaturesF32::default();
+        for _ in 0..8 {
+            let features = a.process(&mut input.clone(), &params).unwrap();
+            assert!(b.process_f32_out(&mut input.clone(), &params, &mut out));
+
+            let close = |x: &[f32], y: &[f64]| {
+                x.iter()
+                    .zip(y.iter())
+                    .all(|(&x, &y)| (x as f64 - y).abs() <= 1e-6 * y.abs().max(1e-30))
+            };
+            assert!(close(&out.amplitudes, features.get_amplitudes(0)));
+            assert!(close(&out.energy, features.get_energy()));
+            assert!(close(&out.scales, features.get_scales()));
+            assert_eq!(out.frame_count, features.get_frame_count());
+        }
+    }
+
+    #[test]
+    fn process_raw() {
+        let params = AnalyzerParams::default();
+        let mut raw = Analyzer::new(128, 128, 16, 2);
+        let mut filtered = Analyzer::new(128, 128, 16, 2);
+
+        use std::f64::consts::PI;
+        let tone: Frame = (0..128)
+            .map(|x| (x as f64 * 16. * PI / 128.).sin())
+            .collect();
+        for _ in 0..4 {
+            raw.process_raw(&Frame::new(128), &params);
+            filtered.process(&mut Frame::new(128), &params);
+        }
+
+        // the raw bars settle within the first block of the tone
+        let first = raw.process_raw(&tone, &params).unwrap().clone();
+        let second = raw.process_raw(&tone, &params).unwrap().clone();
+        assert!(first.iter().sum::<f64>() > 0.);
+        assert_eq!(first, second);
+
+        // while the filtered amplitudes are still rising
+        let total = |f: Features| f.get_amplitudes(0).iter().sum::<f64>();
+        let first = total(filtered.process(&mut tone.clone(), &params).unwrap());
+        let second = total(filtered.process(&mut tone.clone(), &params).unwrap());
+        assert!(second > 1.1 * first, "{} {}", first, second);
+    }
+
+    #[test]
+    fn loop_point() {
+        // a clip of 1000 samples which starts at a trough and ends at a peak, so looping it
+        // jumps from 1 to -1
+        use std::f64::consts::PI;
+        const STEP: f64 = 15. * PI / 1000.;
+        let tone = |x: usize| (x as f64 * STEP - PI / 2.).sin();
+        let clip: Vec<f64> = (0..1000).map(tone).collect();
+        let continuous: Vec<f64> = (0..3000).map(tone).collect();
+
+        let high_energy = |input: &[f64], fade_len: Option<usize>| {
+            let params = AnalyzerParams::default();
+            let mut a = Analyzer::new(256, 64, 16, 2);
+            let mut peak = 0f64;
+            for (n, pass) in input.chunks(1000).enumerate() {
+                if n > 0 {
+                    if let Some(fade_len) = fade_len {
+                        a.loop_point(fade_len);
+                    }
+                }
+                for frame in pass.chunks(64) {
+                    if let Some(raw) = a.process_raw(&frame.to_vec().into(), &params) {
+                        peak = peak.max(raw[8..].iter().sum());
+                    }
+                }
+            }
+            peak
+        };
+
+        let looped: Vec<f64> = clip.iter().cycle().take(3000).cloned().collect();
+        let reference = high_energy(&continuous, None);
+        assert!(high_energy(&looped, None) > 3. * reference);
+        assert!(high_energy(&looped, Some(32)) < 1.5 * reference);
+    }
+
+    #[test]
+    fn finish() {
+        let params = AnalyzerParams::default();
+        let mut a = Analyzer::new(128, 128, 16, 2);
+        let file: Vec<f64> = (0..128 * 5 + 50).map(|x| (x as f64 / 3.).sin()).collect();
+
+        let mut emitted = 0;
+        for chunk in file.chunks(128) {
+            if a.process(&mut chunk.to_vec().into(), &params).is_some() {
+                emitted += 1;
+            }
+        }
+        assert_eq!(emitted, 5);
+
+        let last = a.finish(&params).unwrap();
+        assert_eq!(last.get_frame_count(), 6);
+        assert!(a.finish(&params).is_none());
+    }
+
+    #[test]
+    fn lerp() {
+        let a = AnalyzerParams::default();
+        let mut b = AnalyzerParams::default();
+        b.fs.amp_filter = FilterParams::new(32., 0.5);
+        b.boost.kp = 0.5;
+        b.fs.gain_control.ki = 0.3;
+        b.fs.noise_window = 100;
+        b.output_order = OutputOrder::HighToLow;
+
+        assert_eq!(AnalyzerParams::lerp(&a, &b, 0.), a);
+        assert_eq!(AnalyzerParams::lerp(&a, &b, 1.), b);
+
+        let mid = AnalyzerParams::lerp(&a, &b, 0.25);
+        let coefficients = mid.fs.amp_filter.get_coefficients();
+        assert_eq!(coefficients, vec![14., 0.875]);
+        assert_eq!(mid.fs.amp_filter, FilterParams::new(14., 0.875));
+        assert!(mid.boost.kp > a.boost.kp && mid.boost.kp < b.boost.kp);
+        assert!(mid.fs.gain_control.ki > 0.1 && mid.fs.gain_control.ki < 0.3);
+        assert_eq!(mid.fs.noise_window, 325);
+        assert_eq!(mid.output_order, OutputOrder::LowToHigh);
+    }
+
+    #[test]
+    fn warmup_passes() {
+        use std::f64::consts::PI;
+        let tone: Frame = (0..128)
+            .map(|x| (x as f64 * 16. * PI / 128.).sin())
+            .collect();
+        // hold the gains fixed, which take far longer to settle than the filters
+        let cold = AnalyzerParams {
+            boost: GainControllerParams {
+                kp: 0.,
+                ki: 0.,
+                kd: 0.,
+                ..Default::default()
+            },
+            fs: FrequencySensorParams {
+                gain_enabled: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let warm = AnalyzerParams {
+            warmup_passes: 32,
+            ..cold
+        };
+
+        let first = |params: &AnalyzerParams| {
+            let mut a = Analyzer::new(128, 128, 16, 2);
+            a.process(&mut tone.clone(), params).unwrap()
+        };
+        let mut a = Analyzer::new(128, 128, 16, 2);
+        for _ in 0..64 {
+            a.process(&mut tone.clone(), &cold);
+        }
+        let steady = a.get_features().get_amplitudes(0).clone();
+        let distance = |f: &Features| -> f64 {
+            f.get_amplitudes(0)
+                .iter()
+                .zip(steady.iter())
+                .map(|(x, s)| (x - s).abs())
+                .sum()
+        };
+
+        let cold_distance = distance(&first(&cold));
+        let warm_distance = distance(&first(&warm));
+        assert!(
+            warm_distance < 0.5 * cold_distance,
+            "{} {}",
+            warm_distance,
+            cold_distance
+        );
+    }
+
+    #[test]
+    fn config_for() {
+        let (fft_size, block_size) = Analyzer::config_for(44100., 60., 20.);
+        assert_eq!(fft_size, 4096);
+        assert!(44100. / fft_size as f64 <= 20.);
+
+        // after the window fills, one second of audio emits about 60 frames
+        let mut a = Analyzer::new(fft_size, block_size, 16, 2);
+        assert_eq!(block_size, 735);
+        let frame = Frame::new(147);
+        let mut emitted = 0;
+        for i in 0..600 {
+            let got = a.process(&mut frame.clone(), &Default::default());
+            if i >= 300 && got.is_some() {
+                emitted += 1;
+            }
+        }
+        assert!((emitted - 60i32).abs() <= 1, "{}", emitted);
+    }
+
+    #[test]
+    fn process_in_place() {
+        use crate::testutil::alloc::count_allocations;
+        use std::f64::consts::PI;
+
+        let tone: Vec<f64> = (0..64).map(|x| (x as f64 * PI / 8.).sin()).collect();
+        let params = AnalyzerParams::default();
+        let mut a = Analyzer::new(256, 64, 16, 4);
+        let mut b = Analyzer::new(256, 64, 16, 4);
+        let mut frame = vec![0f64; 64];
+
+        for i in 0..100 {
+            frame.copy_from_slice(&tone);
+            let allocations = count_allocations(|| {
+                a.process_in_place(&mut frame, &params);
+            });
+            if i >= 8 {
+                assert_eq!(allocations, 0, "block {}", i);
+            }
+
+            let expected = b.process(&mut Frame::from(tone.clone()), &params);
+            if let Some(expected) = expected {
+                let got = a.get_features();
+                assert_eq!(got.get_amplitudes(0), expected.get_amplitudes(0));
+                assert_eq!(got.get_scales(), expected.get_scales());
+                assert_eq!(got.get_energy(), expected.get_energy());
+            }
+        }
+    }
+
+    #[test]
+    fn reset() {
+        let mut a = Analyzer::new(64, 64, 8, 2);
+        let input: Frame = (0..64).map(|x| (x as f64 / 3.).sin()).collect();
+        for _ in 0..5 {
+            a.process(&mut input.clone(), &Default::default());
+        }
+        a.reset();
+        assert_eq!(a.get_features().get_frame_count(), 0);
+
+        let mut fresh = Analyzer::new(64, 64, 8, 2);
+        for _ in 0..3 {
+            let expected = fresh.process(&mut input.clone(), &Default::default());
+            assert_eq!(
+                a.process(&mut input.clone(), &Default::default())
+                    .map(|f| f.get_amplitudes(0).clone()),
+                expected.map(|f| f.get_amplitudes(0).clone())
+            );
+        }
+    }
+
+    #[test]
+    fn with_sample_rate() {
+        let a = Analyzer::with_sample_rate(1024, 512, 16, 2, 48000., 32., 24000.);
+        let centers = a.bucketer.center_frequencies();
+        let expected = Bucketer::with_sample_rate(1024, 16, 32., 24000., 48000.);
+        assert_eq!(centers, expected.center_frequencies());
+        assert!(*centers.last().unwrap() < 24000.);
+        assert!(*centers.last().unwrap() > 22000. / 2.);
+
+        // a 1kHz tone at 48kHz lands in the bucket for 1kHz
+        let mut a = Analyzer::with_sample_rate(1024, 1024, 16, 2, 48000., 32., 24000.);
+        let bucket = a.bucketer.bucket_for_frequency(1000.);
+        let tone: Frame = (0..1024)
+            .map(|i| (2. * PI * 1000. * i as f64 / 48000.).sin())
+            .collect();
+        let mut raw = Vec::new();
+        for _ in 0..2 {
+            if let Some(r) = a.process_raw(&tone, &Default::default()) {
+                raw = r.clone();
+            }
+        }
+        let loudest = (0..raw.len())
+            .max_by(|&i, &j| raw[i].total_cmp(&raw[j]))
+            .unwrap();
+        assert_eq!(loudest, bucket);
+    }
+
+    #[test]
+    fn process_samples() {
+        let input: Frame = (0..128).map(|x| (x as f64 * 2. * PI / 16.).cos()).collect();
+        let samples: Vec<f32> = input.iter().map(|x| *x as f32).collect();
+        let params = AnalyzerParams::default();
+
+        let mut a = Analyzer::new(128, 128, 16, 2);
+        let mut b = Analyzer::new(128, 128, 16, 2);
+        for _ in 0..8 {
+            let mut frame: Frame = samples.iter().map(|s| *s as f64).collect();
+            let fa = a.process(&mut frame, &params).unwrap();
+            let fb = b.process_samples(&samples, &params).unwrap();
+            assert_eq!(fa.get_amplitudes(0), fb.get_amplitudes(0));
+            assert_eq!(fa.get_energy(), fb.get_energy());
+        }
+    }
+
+    #[test]
+    fn process_ref() {
+        let input: Frame = (0..128).map(|x| (x as f64 * 2. * PI / 16.).cos()).collect();
+        let params = AnalyzerParams::default();
+
+        let mut a = Analyzer::new(256, 128, 16, 2);
+        let mut b = Analyzer::new(256, 128, 16, 2);
+        for _ in 0..8 {
+            let features = a.process(&mut input.clone(), &params);
+            assert_eq!(
+                b.process_ref(&mut input.clone(), &params),
+                features.is_some()
+            );
+            if let Some(features) = features {
+                let fb = b.get_features();
+                assert_eq!(features.get_amplitudes(0), fb.get_amplitudes(0));
+                assert_eq!(features.get_energy(), fb.get_energy());
+            }
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn params_json_round_trip() {
+        fn filters(p: &AnalyzerParams) -> Vec<FilterParams> {
+            vec![
+                p.boost.attack_filter,
+                p.boost.release_filter,
+                p.boost_attack,
+                p.boost_release,
+                p.fs.amp_filter,
+                p.fs.amp_feedback,
+                p.fs.diff_filter,
+                p.fs.diff_feedback,
+                p.fs.pos_scale_filter,
+                p.fs.neg_scale_filter,
+                p.fs.scale_variance_filter,
+                p.fs.peak_attack,
+                p.fs.peak_release,
+                p.fs.display_filter,
+            ]
+        }
+
+        let params = AnalyzerParams::default();
+        let loaded = AnalyzerParams::from_json(&params.to_json().unwrap()).unwrap();
+        for (f, g) in filters(&params).iter().zip(filters(&loaded).iter()) {
+            assert_eq!(f.a.to_bits(), g.a.to_bits());
+            assert_eq!(f.b.to_bits(), g.b.to_bits());
+        }
+        assert_eq!(params, loaded);
+    }
+
+    #[test]
+    fn process_spectrum() {
+        let input: Frame = (0..128).map(|x| (x as f64 * 2. * PI / 16.).cos()).collect();
+        let params = AnalyzerParams {
+            boost: GainControllerParams {
+                kp: 0.,
+                ki: 0.,
+                kd: 0.,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let mut a = Analyzer::new(256, 128, 16, 2);
+        let mut b = Analyzer::new(256, 128, 16, 2);
+        let mut sfft = crate::sfft::SlidingFFT::new(256);
+        assert!(b.process_spectrum(&Spectrum::new(256), &params).is_none());
+        for _ in 0..8 {
+            let mut frame = input.clone();
+            let fa = a.process(&mut frame, &params);
+            sfft.push_input(&frame);
+            if let Some(fa) = fa {
+                let fb = b.process_spectrum(sfft.process(), &params).unwrap();
+                assert_eq!(fa.get_amplitudes(0), fb.get_amplitudes(0));
+                assert_eq!(fa.get_energy(), fb.get_energy());
+            }
+        }
+    }
+
+    #[test]
+    fn last_spectrum() {
+        let mut a = Analyzer::new(256, 128, 16, 2);
+        let params = AnalyzerParams::default();
+        let mut spectrum = Spectrum::new(128);
+        spectrum[40] = 1.;
+        a.process_spectrum(&spectrum, &params).unwrap();
+
+        let mut bucketer = Bucketer::new(128, 16, 32., 22000.);
+        assert_eq!(a.last_spectrum(), &bucketer.bucket(&spectrum).to_vec());
+        assert_ne!(a.last_spectrum(), a.get_features().get_amplitudes(0));
+
+        a.reset();
+        assert!(a.last_spectrum().iter().all(|x| *x == 0.));
+    }
 }
@@ -1,9 +1,145 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::types::{Buckets, Spectrum};
+
+/// BucketMode determines how the bins of each bucket are combined. `Mean` combines them with the
+/// bucketer's `Aggregation`, which averages them by default. `Sum` adds them, so the total
+/// across buckets equals the total across the input regardless of the number of buckets.
+/// `PowerDb` expects a power spectrum, adds the power of the bins and returns it in decibels,
+/// no lower than `floor_db`.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+pub enum BucketMode {
+    #[default]
+    Mean,
+    Sum,
+    PowerDb {
+        floor_db: f64,
+    },
+}
+
+/// Aggregation is the function that combines the bins of each bucket in `BucketMode::Mean`.
+/// `Max` takes the largest, e.g. for peak meters, and `Rms` the root mean square.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+pub enum Aggregation {
+    #[default]
+    Mean,
+    Max,
+    Sum,
+    Rms,
+}
+
+/// DcMode determines what happens to the DC bin, the zero'th element of the spectrum. `Keep`
+/// leaves it in the first bucket. `Drop` leaves it out, and `Fold` merges it into the first
+/// bucket which has other bins. In either case, when the DC bin would be a bucket of its own
+/// that bucket is removed, so the bucketer returns one bucket fewer.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+pub enum DcMode {
+    #[default]
+    Keep,
+    Drop,
+    Fold,
+}
+
+/// Scale is the frequency scale on which `with_scale` spaces the bucket edges evenly. `Log2` is
+/// the logarithmic scale used by `new`, and `Mel` is the perceptual scale
+/// 2595 * log10(1 + f / 700).
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+pub enum Scale {
+    #[default]
+    Log2,
+    Mel,
+    Linear,
+}
+
+impl Scale {
+    fn forward(self, f: f64) -> f64 {
+        match self {
+            Scale::Log2 => to_log_scale(f),
+            Scale::Mel => 2595. * (1. + f / 700.).log10(),
+            Scale::Linear => f,
+        }
+    }
+
+    fn inverse(self, x: f64) -> f64 {
+        match self {
+            Scale::Log2 => from_log_scale(x),
+            Scale::Mel => 700. * (10f64.powf(x / 2595.) - 1.),
+            Scale::Linear => x,
+        }
+    }
+}
+
+/// Weighting is a frequency weighting applied to each bucket, at its center frequency, so that
+/// the buckets better match perceived loudness. `A` and `C` are the IEC 61672 A and C
+/// weightings, normalized to 0dB at 1kHz.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+pub enum Weighting {
+    #[default]
+    None,
+    A,
+    C,
+}
+
+impl Weighting {
+    // db returns the weighting's gain in decibels at frequency f
+    fn db(self, f: f64) -> f64 {
+        let f2 = f * f;
+        let (p1, p2, p3, p4) = (
+            20.6f64.powi(2),
+            107.7f64.powi(2),
+            737.9f64.powi(2),
+            12194f64.powi(2),
+        );
+        match self {
+            Weighting::None => 0.,
+            Weighting::A => {
+                let r = p4 * f2 * f2 / ((f2 + p1) * ((f2 + p2) * (f2 + p3)).sqrt() * (f2 + p4));
+                20. * r.log10() + 2.
+            }
+            Weighting::C => {
+                let r = p4 * f2 / ((f2 + p1) * (f2 + p4));
+                20. * r.log10() + 0.06
+            }
+        }
+    }
+}
+
 /// Bucketer takes an FFT frame of a given size and returns a given number of frequency bins
-/// whose indices are caculated using a logrithmic scale. The zero'th element in the
-/// spectrum is always its own bucket, so the bucketer always returns N+1 buckets.
+/// whose indices are caculated using a logrithmic scale, or another `Scale`. The zero'th element
+/// in the spectrum is always its own bucket, so the bucketer always returns N+1 buckets.
+/// By default each bucket is the mean of its bins, see `BucketMode` and `Aggregation`.
 pub struct Bucketer {
     pub indices: Vec<usize>,
-    output: Vec<f64>,
+    output: Buckets,
+
+    input_size: usize,
+    f_min: f64,
+    f_max: f64,
+    // the frequency of the end of the input
+    nyquist: f64,
+    mode: BucketMode,
+    aggregation: Aggregation,
+    dc_mode: DcMode,
+
+    // for bucket_weighted, the first bin and the weights of the bins of each bucket's triangle
+    weights: Vec<(usize, Vec<f64>)>,
+    // for a chroma bucketer, the pitch class of each bin, or None for bins which are dropped
+    pitch_classes: Vec<Option<usize>>,
+    reference_a4: f64,
+
+    weighting: Weighting,
+    // the gain of each bucket in decibels for the weighting
+    weighting_db: Vec<f64>,
+}
+
+// the number of semitones from C up to A
+const A_PITCH_CLASS: f64 = 9.;
+
+// pitch_class returns the pitch class, from C at 0 to B at 11, of the semitone nearest to f
+fn pitch_class(f: f64, reference_a4: f64) -> usize {
+    let semitones = (12. * (f / reference_a4).log2()).round() + A_PITCH_CLASS;
+    semitones.rem_euclid(12.) as usize
 }
 
 fn to_log_scale(x: f64) -> f64 {
@@ -14,13 +150,167 @@ fn from_log_scale(x: f64) -> f64 {
     (2f64).powf(x) + 1.
 }
 
+// to_warped_scale maps a frequency onto a scale between linear, at warp 0, and logarithmic, at
+// warp 1, using a Box-Cox transform. At warp 1 it is the original log scale.
+fn to_warped_scale(x: f64, warp: f64) -> f64 {
+    if warp >= 1. {
+        return to_log_scale(x);
+    }
+    let p = 1. - warp;
+    ((x + 1.).powf(p) - 1.) / p
+}
+
+fn from_warped_scale(x: f64, warp: f64) -> f64 {
+    if warp >= 1. {
+        return from_log_scale(x);
+    }
+    let p = 1. - warp;
+    (x * p + 1.).powf(1. / p) - 1.
+}
+
 impl Bucketer {
+    /// new creates a bucketer for an input of `input_size` bins which span from 0 up to `f_max`,
+    /// i.e. `f_max` is taken to be the Nyquist frequency. See `with_sample_rate` for a range of
+    /// frequencies below the Nyquist frequency.
     pub fn new(input_size: usize, buckets: usize, f_min: f64, f_max: f64) -> Bucketer {
-        let output = vec![0f64; buckets];
+        Bucketer::with_warp(input_size, buckets, f_min, f_max, 1.)
+    }
+
+    /// with_sample_rate creates a log scale bucketer, like `new`, for the spectrum of an fft of
+    /// `fft_size` samples at `sample_rate`, whose buckets span from `f_min` to `f_max`. `f_max`
+    /// is clamped to the Nyquist frequency, and bins above it are left out of the last bucket.
+    pub fn with_sample_rate(
+        fft_size: usize,
+        buckets: usize,
+        f_min: f64,
+        f_max: f64,
+        sample_rate: f64,
+    ) -> Bucketer {
+        let nyquist = sample_rate / 2.;
+        Bucketer::build(
+            fft_size / 2,
+            buckets,
+            f_min,
+            f_max.min(nyquist),
+            nyquist,
+            to_log_scale,
+            from_log_scale,
+        )
+    }
+
+    /// with_scale creates a bucketer whose bucket edges are spaced evenly on the given scale.
+    pub fn with_scale(
+        input_size: usize,
+        buckets: usize,
+        f_min: f64,
+        f_max: f64,
+        scale: Scale,
+    ) -> Bucketer {
+        Bucketer::build(
+            input_size,
+            buckets,
+            f_min,
+            f_max,
+            f_max,
+            |f| scale.forward(f),
+            |x| scale.inverse(x),
+        )
+    }
+
+    /// with_triangular creates a bucketer like `with_scale` for `bucket_weighted`, whose buckets
+    /// are overlapping triangles, as in a mel filterbank. Each triangle has its apex at the
+    /// center of its bucket and its feet at the centers of the neighboring buckets, so
+    /// neighbors overlap by half. The weights are stored for every bin under each triangle,
+    /// which takes around twice `input_size` values in total.
+    pub fn with_triangular(
+        input_size: usize,
+        buckets: usize,
+        f_min: f64,
+        f_max: f64,
+        scale: Scale,
+    ) -> Bucketer {
+        let mut b = Bucketer::with_scale(input_size, buckets, f_min, f_max, scale);
+        b.weights = b.triangles();
+        b
+    }
+
+    /// chroma creates a bucketer which returns a 12 bucket chromagram of the spectrum of an fft
+    /// of `fft_size` samples at `sample_rate`, with A tuned to `reference_a4` Hz. Each bin is
+    /// folded into the pitch class, from C to B, of its nearest semitone, and each bucket is the
+    /// sum of its bins regardless of the `BucketMode`. Bins below the lowest pitch whose
+    /// semitones are at least a bin apart can't be told apart from their neighbors and are
+    /// dropped.
+    pub fn chroma(fft_size: usize, sample_rate: f64, reference_a4: f64) -> Bucketer {
+        let input_size = fft_size / 2;
+        let nyquist = sample_rate / 2.;
+        let bin_width = nyquist / input_size as f64;
+        let f_min = bin_width / (2f64.powf(1. / 12.) - 1.);
+        let pitch_classes = (0..input_size)
+            .map(|bin| {
+                let f = bin as f64 * bin_width;
+                if f < f_min {
+                    return None;
+                }
+                Some(pitch_class(f, reference_a4))
+            })
+            .collect();
+        Bucketer {
+            indices: Vec::new(),
+            output: Buckets::new(12),
+            input_size,
+            f_min,
+            f_max: nyquist,
+            nyquist,
+            mode: BucketMode::Sum,
+            aggregation: Aggregation::default(),
+            dc_mode: DcMode::Drop,
+            weights: Vec::new(),
+            pitch_classes,
+            reference_a4,
+            weighting: Weighting::default(),
+            weighting_db: Vec::new(),
+        }
+    }
+
+    /// with_warp creates a bucketer whose bucket edges are spaced evenly on a scale between
+    /// linear in frequency, when `warp` is 0, and logarithmic, when it is 1, as used by `new`.
+    pub fn with_warp(
+        input_size: usize,
+        buckets: usize,
+        f_min: f64,
+        f_max: f64,
+        warp: f64,
+    ) -> Bucketer {
+        if !(0. ..=1.).contains(&warp) {
+            panic!("warp must be between 0 and 1");
+        }
+        Bucketer::build(
+            input_size,
+            buckets,
+            f_min,
+            f_max,
+            f_max,
+            |x| to_warped_scale(x, warp),
+            |x| from_warped_scale(x, warp),
+        )
+    }
+
+    // build spaces the bucket edges evenly on the scale given by to_scale and its inverse,
+    // from_scale, for an input of input_size bins spanning from 0 up to nyquist
+    fn build<T: Fn(f64) -> f64, F: Fn(f64) -> f64>(
+        input_size: usize,
+        buckets: usize,
+        f_min: f64,
+        f_max: f64,
+        nyquist: f64,
+        to_scale: T,
+        from_scale: F,
+    ) -> Bucketer {
+        let output = Buckets::new(buckets);
         let mut indices = vec![0; buckets - 1];
 
-        let s_min = to_log_scale(f_min);
-        let s_max = to_log_scale(f_max);
+        let s_min = to_scale(f_min);
+        let s_max = to_scale(f_max);
 
         let buckets_f = buckets as f64;
         let input_size_f = input_size as f64;
@@ -33,8 +323,8 @@ impl Bucketer {
         for i in 0..indices.len() {
             let adj = space - delta * offset / buckets_f;
 
-            let v = from_log_scale((i + 1) as f64 * adj + s_min + offset * delta);
-            let mut idx = (input_size_f * v / f_max).ceil() as usize;
+            let v = from_scale((i + 1) as f64 * adj + s_min + offset * delta);
+            let mut idx = (input_size_f * v / nyquist).ceil() as usize;
 
             if idx <= last_idx {
                 idx = last_idx + 1;
@@ -48,21 +338,376 @@ impl Bucketer {
             last_idx = idx;
         }
 
-        Bucketer { indices, output }
+        Bucketer {
+            indices,
+            output,
+            input_size,
+            f_min,
+            f_max,
+            nyquist,
+            mode: BucketMode::default(),
+            aggregation: Aggregation::default(),
+            dc_mode: DcMode::default(),
+            weights: Vec::new(),
+            pitch_classes: Vec::new(),
+            reference_a4: 440.,
+            weighting: Weighting::default(),
+            weighting_db: Vec::new(),
+        }
     }
 
-    /// bucket returns the input of the input split into `size` bins
-    pub fn bucket(&mut self, input: &Vec<f64>) -> &mut Vec<f64> {
-        for i in 0..self.output.len() {
-            let start = if i == 0 { 0 } else { self.indices[i - 1] };
-            let stop = if i == self.output.len() - 1 {
-                input.len()
+    /// from_center_frequencies creates a bucketer with a bucket for each of `centers`, in Hz,
+    /// whose edges lie at the midpoints between consecutive centers. The input is expected to
+    /// span from 0 up to the Nyquist frequency of `sample_rate`. Centers must be strictly
+    /// increasing, lie within that range, and be far enough apart that every bucket gets at
+    /// least one bin.
+    pub fn from_center_frequencies(
+        input_size: usize,
+        centers: &[f64],
+        sample_rate: f64,
+    ) -> Result<Bucketer> {
+        let f_max = sample_rate / 2.;
+        if centers.is_empty() {
+            return Err(anyhow!("at least one center frequency is required"));
+        }
+        if centers.windows(2).any(|w| w[1] <= w[0]) {
+            return Err(anyhow!("center frequencies must be strictly increasing"));
+        }
+        if centers[0] <= 0. || centers[centers.len() - 1] >= f_max {
+            return Err(anyhow!(
+                "center frequencies must be within (0, {}) Hz",
+                f_max
+            ));
+        }
+
+        let bin_width = f_max / input_size as f64;
+        let mut indices = Vec::with_capacity(centers.len() - 1);
+        let mut last_idx = 0;
+        for w in centers.windows(2) {
+            let idx = ((w[0] + w[1]) / 2. / bin_width).round() as usize;
+            if idx <= last_idx || idx >= input_size {
+                return Err(anyhow!(
+                    "center frequencies {} and {} are too close for a bin width of {} Hz",
+                    w[0],
+                    w[1],
+                    bin_width
+                ));
+            }
+            indices.push(idx);
+            last_idx = idx;
+        }
+
+        Ok(Bucketer {
+            indices,
+            output: Buckets::new(centers.len()),
+            input_size,
+            f_min: 0.,
+            f_max,
+            nyquist: f_max,
+            mode: BucketMode::default(),
+            aggregation: Aggregation::default(),
+            dc_mode: DcMode::default(),
+            weights: Vec::new(),
+            pitch_classes: Vec::new(),
+            reference_a4: 440.,
+            weighting: Weighting::default(),
+            weighting_db: Vec::new(),
+        })
+    }
+
+    pub fn set_mode(&mut self, mode: BucketMode) {
+        self.mode = mode;
+    }
+
+    pub fn get_aggregation(&self) -> Aggregation {
+        self.aggregation
+    }
+
+    /// set_aggregation sets how the bins of each bucket are combined in `BucketMode::Mean`. It
+    /// does not change the bucket layout.
+    pub fn set_aggregation(&mut self, aggregation: Aggregation) {
+        self.aggregation = aggregation;
+    }
+
+    /// set_dc_mode sets how the DC bin is handled, which may change the number of buckets
+    /// returned. See `DcMode`.
+    pub fn set_dc_mode(&mut self, dc_mode: DcMode) {
+        if !self.pitch_classes.is_empty() {
+            return;
+        }
+        self.dc_mode = dc_mode;
+        let len = self.indices.len() + 1 - self.skipped_buckets();
+        self.output = Buckets::new(len);
+        if !self.weights.is_empty() {
+            self.weights = self.triangles();
+        }
+        self.set_weighting(self.weighting);
+    }
+
+    /// get_input_size returns the number of spectrum bins the bucketer expects as input.
+    pub fn get_input_size(&self) -> usize {
+        self.input_size
+    }
+
+    pub fn get_weighting(&self) -> Weighting {
+        self.weighting
+    }
+
+    /// set_weighting sets the frequency weighting multiplied into each bucket, at the bucket's
+    /// center frequency. Buckets are taken to be amplitudes, except in `BucketMode::PowerDb`
+    /// where the weighting is added in decibels. Chroma buckets are not weighted.
+    pub fn set_weighting(&mut self, weighting: Weighting) {
+        self.weighting = weighting;
+        self.weighting_db = if weighting == Weighting::None || !self.pitch_classes.is_empty() {
+            Vec::new()
+        } else {
+            self.center_frequencies()
+                .iter()
+                .map(|&f| weighting.db(f))
+                .collect()
+        };
+    }
+
+    // weigh applies the weighting of bucket i to its value x
+    fn weigh(&self, i: usize, x: f64) -> f64 {
+        match self.weighting_db.get(i) {
+            None => x,
+            Some(db) => match self.mode {
+                BucketMode::PowerDb { .. } => x + db,
+                _ => x * 10f64.powf(db / 20.),
+            },
+        }
+    }
+
+    // triangles computes the weights of each bucket's triangle, normalized to sum to 1. Bins
+    // are positioned at their centers, and the outer feet of the first and last triangles are
+    // at the outer edges of their buckets.
+    fn triangles(&self) -> Vec<(usize, Vec<f64>)> {
+        let len = self.output.len();
+        let ranges: Vec<(usize, usize)> = (0..len)
+            .map(|i| self.bucket_range(i, self.input_size))
+            .collect();
+        let centers: Vec<f64> = ranges
+            .iter()
+            .map(|&(start, stop)| (start + stop) as f64 / 2.)
+            .collect();
+        (0..len)
+            .map(|i| {
+                let left = if i == 0 {
+                    ranges[0].0 as f64
+                } else {
+                    centers[i - 1]
+                };
+                let right = if i == len - 1 {
+                    ranges[i].1 as f64
+                } else {
+                    centers[i + 1]
+                };
+                let start = left.floor() as usize;
+                let stop = (right.ceil() as usize).min(self.input_size);
+                let mut weights: Vec<f64> = (start..stop)
+                    .map(|b| {
+                        let x = b as f64 + 0.5;
+                        let w = if x < centers[i] {
+                            (x - left) / (centers[i] - left)
+                        } else {
+                            (right - x) / (right - centers[i])
+                        };
+                        w.max(0.)
+                    })
+                    .collect();
+                let total: f64 = weights.iter().sum();
+                if total > 0. {
+                    for w in weights.iter_mut() {
+                        *w /= total;
+                    }
+                }
+                (start, weights)
+            })
+            .collect()
+    }
+
+    // skipped_buckets is 1 when the DC bin is a bucket of its own which has been removed
+    fn skipped_buckets(&self) -> usize {
+        if self.dc_mode != DcMode::Keep && self.indices.first() == Some(&1) {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// set_conserve_energy switches between `BucketMode::Sum` and `BucketMode::Mean`.
+    pub fn set_conserve_energy(&mut self, conserve_energy: bool) {
+        self.mode = if conserve_energy {
+            BucketMode::Sum
+        } else {
+            BucketMode::Mean
+        };
+    }
+
+    // aggregate combines the bins of a bucket. A bucket may have no bins when there are more
+    // buckets than the input has bins above f_min, in which case it is silent: 0, or the floor
+    // in `BucketMode::PowerDb`.
+    fn aggregate(&self, bins: &[f64]) -> f64 {
+        if bins.is_empty() {
+            return match self.mode {
+                BucketMode::PowerDb { floor_db } => floor_db,
+                _ => 0.,
+            };
+        }
+        let sum: f64 = bins.iter().sum();
+        match self.mode {
+            BucketMode::Mean => match self.aggregation {
+                Aggregation::Mean => sum / bins.len() as f64,
+                Aggregation::Max => bins.iter().cloned().fold(f64::MIN, f64::max),
+                Aggregation::Sum => sum,
+                Aggregation::Rms => {
+                    (bins.iter().map(|x| x * x).sum::<f64>() / bins.len() as f64).sqrt()
+                }
+            },
+            BucketMode::Sum => sum,
+            BucketMode::PowerDb { floor_db } => (10. * sum.log10()).max(floor_db),
+        }
+    }
+
+    fn bin_frequency(&self, bin: f64) -> f64 {
+        bin * self.nyquist / self.input_size as f64
+    }
+
+    // stop_bin returns the end of the last bucket, which is the end of the input unless f_max
+    // is below the Nyquist frequency
+    fn stop_bin(&self, input_len: usize) -> usize {
+        if self.f_max < self.nyquist {
+            let stop = (self.input_size as f64 * self.f_max / self.nyquist).ceil() as usize;
+            input_len.min(stop)
+        } else {
+            input_len
+        }
+    }
+
+    // bucket_range returns the range of bins of bucket i, for an input of input_len bins
+    fn bucket_range(&self, i: usize, input_len: usize) -> (usize, usize) {
+        let j = i + self.skipped_buckets();
+        let mut start = if j == 0 { 0 } else { self.indices[j - 1] };
+        let stop = if i == self.output.len() - 1 {
+            self.stop_bin(input_len)
+        } else {
+            self.indices[j]
+        };
+        if i == 0 {
+            match self.dc_mode {
+                DcMode::Keep => {}
+                DcMode::Drop => start = 1,
+                DcMode::Fold => start = 0,
+            }
+        }
+        (start, stop)
+    }
+
+    /// center_frequencies returns the frequency in Hz at the middle of each bucket's range of
+    /// input bins.
+    /// For a chroma bucketer they are the frequencies of the pitch classes from C4 to B4.
+    pub fn center_frequencies(&self) -> Vec<f64> {
+        if !self.pitch_classes.is_empty() {
+            return (0..12)
+                .map(|i| self.reference_a4 * 2f64.powf((i as f64 - A_PITCH_CLASS) / 12.))
+                .collect();
+        }
+        (0..self.output.len())
+            .map(|i| {
+                let (start, stop) = self.bucket_range(i, self.input_size);
+                self.bin_frequency((start + stop) as f64 / 2.)
+            })
+            .collect()
+    }
+
+    /// bucket_for_frequency returns the index of the bucket containing `freq`. Frequencies below
+    /// `f_min` map to the first bucket and frequencies above `f_max` map to the last.
+    /// For a chroma bucketer it is the pitch class of `freq` itself, rather than of its bin.
+    pub fn bucket_for_frequency(&self, freq: f64) -> usize {
+        if !self.pitch_classes.is_empty() {
+            return if freq > 0. {
+                pitch_class(freq, self.reference_a4)
             } else {
-                self.indices[i]
+                0
             };
+        }
+        let last = self.output.len() - 1;
+        if freq < self.f_min {
+            return 0;
+        }
+        if freq >= self.f_max {
+            return last;
+        }
+        let bin = (freq * self.input_size as f64 / self.nyquist) as usize;
+        (0..last)
+            .position(|i| bin < self.bucket_range(i, self.input_size).1)
+            .unwrap_or(last)
+    }
 
-            let sum: f64 = input[start..stop].iter().sum();
-            self.output[i] = sum / (stop - start) as f64;
+    /// bucket returns the input of the input split into `size` bins
+    pub fn bucket(&mut self, input: &Spectrum) -> &mut Buckets {
+        if !self.pitch_classes.is_empty() {
+            return self.bucket_chroma(input);
+        }
+        for i in 0..self.output.len() {
+            let (start, stop) = self.bucket_range(i, input.len());
+            self.output[i] = self.weigh(i, self.aggregate(&input[start..stop]));
+        }
+
+        &mut self.output
+    }
+
+    fn bucket_chroma(&mut self, input: &[f64]) -> &mut Buckets {
+        for x in self.output.iter_mut() {
+            *x = 0.;
+        }
+        for (x, class) in input.iter().zip(self.pitch_classes.iter()) {
+            if let Some(class) = class {
+                self.output[*class] += x;
+            }
+        }
+
+        &mut self.output
+    }
+
+    /// bucket_weighted returns the weighted mean of the bins under each bucket's triangle, for a
+    /// bucketer created by `with_triangular`. The `BucketMode` and `Aggregation` are not used.
+    pub fn bucket_weighted(&mut self, input: &Spectrum) -> &mut Buckets {
+        if self.weights.is_empty() {
+            panic!("bucket_weighted requires a bucketer created with with_triangular");
+        }
+        for i in 0..self.output.len() {
+            let (start, weights) = &self.weights[i];
+            let x = input[*start..]
+                .iter()
+                .zip(weights.iter())
+                .map(|(x, w)| x * w)
+                .sum();
+            self.output[i] = self.weigh(i, x);
+        }
+
+        &mut self.output
+    }
+
+    /// bucket_partial buckets a spectrum of which only the first `valid_len` bins are available.
+    /// Only buckets whose whole range of bins lies within `valid_len` are updated; the remaining
+    /// buckets keep the values from the last call to `bucket` or `bucket_partial`.
+    /// Every chroma bucket spans the whole input, so they are only updated once it is all valid.
+    pub fn bucket_partial(&mut self, input: &[f64], valid_len: usize) -> &mut Buckets {
+        let valid_len = valid_len.min(input.len());
+        if !self.pitch_classes.is_empty() {
+            if valid_len >= self.input_size {
+                self.bucket_chroma(input);
+            }
+            return &mut self.output;
+        }
+        for i in 0..self.output.len() {
+            let (start, stop) = self.bucket_range(i, self.input_size);
+            if stop > valid_len {
+                break;
+            }
+            self.output[i] = self.weigh(i, self.aggregate(&input[start..stop]));
         }
 
         &mut self.output
@@ -71,22 +716,327 @@ impl Bucketer {
 
 #[cfg(test)]
 mod tests {
-    use super::Bucketer;
+    use super::{Aggregation, BucketMode, Bucketer, DcMode, Scale, Weighting};
 
     #[test]
     fn it_works() {
         let mut b = Bucketer::new(16, 16, 32., 16000.);
         let d = vec![1f64; 16];
 
-        let out = b.bucket(&d);
+        let out = b.bucket(&d.clone().into());
         assert_eq!(out, &d);
 
         let mut b = Bucketer::new(16, 4, 32., 16000.);
-        let out = b.bucket(&d);
+        let out = b.bucket(&d.into());
         assert_eq!(out, &vec![1f64; 4]);
 
-        let out = b.bucket(&(0u8..16).map(f64::from).collect::<Vec<f64>>());
+        let out = b.bucket(&(0u8..16).map(f64::from).collect());
         // dunno if this is "right" but whatever..
         assert_eq!(out, &vec![0f64, 1., 2.5, 9.5]);
     }
+
+    #[test]
+    fn bucket_partial() {
+        // buckets cover bins [0, 1), [1, 2), [2, 4), [4, 16)
+        let mut b = Bucketer::new(16, 4, 32., 16000.);
+        b.bucket(&vec![1f64; 16].into());
+
+        let out = b.bucket_partial(&[2f64; 16], 3);
+        assert_eq!(out, &vec![2f64, 2., 1., 1.]);
+
+        let out = b.bucket_partial(&[3f64; 16], 4);
+        assert_eq!(out, &vec![3f64, 3., 3., 1.]);
+
+        let out = b.bucket_partial(&[4f64; 16], 16);
+        assert_eq!(out, &vec![4f64; 4]);
+    }
+
+    #[test]
+    fn bucket_for_frequency() {
+        // with 16 bins up to 16kHz each bin is 1kHz wide, and the buckets cover bins
+        // [0, 1), [1, 2), [2, 4), [4, 16).
+        let b = Bucketer::new(16, 4, 32., 16000.);
+        assert_eq!(b.center_frequencies(), vec![500., 1500., 3000., 10000.]);
+
+        assert_eq!(b.bucket_for_frequency(1500.), 1);
+        assert_eq!(b.bucket_for_frequency(2500.), 2);
+        assert_eq!(b.bucket_for_frequency(3999.), 2);
+        assert_eq!(b.bucket_for_frequency(4000.), 3);
+
+        assert_eq!(b.bucket_for_frequency(10.), 0);
+        assert_eq!(b.bucket_for_frequency(20000.), 3);
+    }
+
+    #[test]
+    fn conserve_energy() {
+        let input: Vec<f64> = (0..64).map(|x| ((x * 7) % 11) as f64 / 3.).collect();
+        let total: f64 = input.iter().sum();
+
+        for &buckets in &[4, 12] {
+            let mut b = Bucketer::new(64, buckets, 32., 16000.);
+            b.set_conserve_energy(true);
+            let out = b.bucket(&input.clone().into());
+            assert!((out.iter().sum::<f64>() - total).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn from_center_frequencies() {
+        // chromatic scale from A3 to A5
+        let notes: Vec<f64> = (0..25)
+            .map(|n| 220. * (2f64).powf(n as f64 / 12.))
+            .collect();
+        let mut b = Bucketer::from_center_frequencies(4096, &notes, 44100.).unwrap();
+
+        let bin_width = 22050. / 4096.;
+        for (n, &f) in notes.iter().enumerate() {
+            assert_eq!(b.bucket_for_frequency(f), n);
+
+            let mut spectrum = vec![0f64; 4096];
+            spectrum[(f / bin_width) as usize] = 1.;
+            let out = b.bucket(&spectrum.into());
+            let loudest = (0..out.len())
+                .max_by(|&i, &j| out[i].partial_cmp(&out[j]).unwrap())
+                .unwrap();
+            assert_eq!(loudest, n);
+        }
+
+        assert!(Bucketer::from_center_frequencies(4096, &[440., 220.], 44100.).is_err());
+        assert!(Bucketer::from_center_frequencies(4096, &[220., 30000.], 44100.).is_err());
+        assert!(Bucketer::from_center_frequencies(64, &[220., 221., 222.], 44100.).is_err());
+    }
+
+    #[test]
+    fn power_db() {
+        // buckets cover bins [0, 1), [1, 2), [2, 4), [4, 16)
+        let mut b = Bucketer::new(16, 4, 32., 16000.);
+        b.set_mode(BucketMode::PowerDb { floor_db: -100. });
+
+        let mut power = vec![0f64; 16];
+        power[1] = 10.;
+        power[9] = 100.;
+        let out = b.bucket(&power.into());
+        assert_eq!(out[0], -100.);
+        assert!((out[1] - 10.).abs() < 1e-9);
+        // the strong bin isn't diluted by the other 11 bins of its bucket
+        assert!((out[3] - 20.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn warp() {
+        let log = Bucketer::new(512, 16, 32., 22000.);
+        let warped = Bucketer::with_warp(512, 16, 32., 22000., 1.);
+        assert_eq!(log.indices, warped.indices);
+
+        let linear = Bucketer::with_warp(64, 4, 0., 16000., 0.);
+        assert_eq!(linear.indices, vec![16, 32, 48]);
+
+        // in between, the low buckets are wider than log and narrower than linear
+        let half = Bucketer::with_warp(512, 16, 32., 22000., 0.5);
+        let linear = Bucketer::with_warp(512, 16, 32., 22000., 0.);
+        assert!(half.indices[0] > log.indices[0]);
+        assert!(half.indices[0] < linear.indices[0]);
+    }
+
+    #[test]
+    fn dc_mode() {
+        // buckets cover bins [0, 1), [1, 2), [2, 4), [4, 16)
+        let input = (0u8..16).map(f64::from).collect();
+        let mut b = Bucketer::new(16, 4, 32., 16000.);
+
+        b.set_dc_mode(DcMode::Drop);
+        assert_eq!(b.bucket(&input), &vec![1., 2.5, 9.5]);
+        assert_eq!(b.bucket_for_frequency(1500.), 0);
+        assert_eq!(b.center_frequencies(), vec![1500., 3000., 10000.]);
+
+        b.set_dc_mode(DcMode::Fold);
+        assert_eq!(b.bucket(&input), &vec![0.5, 2.5, 9.5]);
+        assert_eq!(b.bucket_for_frequency(500.), 0);
+
+        b.set_dc_mode(DcMode::Keep);
+        assert_eq!(b.bucket(&input), &vec![0., 1., 2.5, 9.5]);
+
+        // when the first bucket has more than the DC bin, only the DC bin is dropped
+        let mut b = Bucketer::from_center_frequencies(16, &[2000., 5000.], 32000.).unwrap();
+        b.set_dc_mode(DcMode::Drop);
+        assert_eq!(b.bucket(&input), &vec![2., 9.5]);
+    }
+
+    #[test]
+    fn with_sample_rate() {
+        // an fft of 32 samples at 32kHz has 16 bins 1kHz wide, so up to 8kHz the buckets match
+        // those of 8 bins up to 8kHz
+        let mut b = Bucketer::with_sample_rate(32, 4, 32., 8000., 32000.);
+        let expected = Bucketer::new(8, 4, 32., 8000.);
+        assert_eq!(b.indices, expected.indices);
+        assert_eq!(b.center_frequencies(), expected.center_frequencies());
+        assert_eq!(
+            b.bucket_for_frequency(5000.),
+            expected.bucket_for_frequency(5000.)
+        );
+
+        // bins above f_max are left out
+        let mut spectrum = vec![1f64; 16];
+        spectrum[12] = 100.;
+        assert_eq!(b.bucket(&spectrum.into()), &vec![1f64; 4]);
+
+        // f_max is clamped to the Nyquist frequency
+        let b = Bucketer::with_sample_rate(32, 4, 32., 30000., 32000.);
+        assert_eq!(b.indices, Bucketer::new(16, 4, 32., 16000.).indices);
+    }
+
+    #[test]
+    fn with_scale() {
+        let log2 = Bucketer::with_scale(256, 16, 32., 16000., Scale::Log2);
+        assert_eq!(log2.indices, Bucketer::new(256, 16, 32., 16000.).indices);
+        let linear = Bucketer::with_scale(256, 16, 32., 16000., Scale::Linear);
+        assert_eq!(
+            linear.indices,
+            Bucketer::with_warp(256, 16, 32., 16000., 0.).indices
+        );
+
+        // mel buckets are wider than log2 buckets at low frequencies, and narrower than linear
+        // buckets
+        let mel = Bucketer::with_scale(256, 16, 32., 16000., Scale::Mel);
+        assert!(mel.indices[0] > log2.indices[0]);
+        assert!(mel.indices[0] < linear.indices[0]);
+
+        let hz = 1234.;
+        let scale = Scale::Mel;
+        assert!((scale.inverse(scale.forward(hz)) - hz).abs() < 1e-9);
+        assert!((scale.forward(1000.) - 1000.).abs() < 1.);
+    }
+
+    #[test]
+    fn bucket_weighted() {
+        let mut b = Bucketer::with_triangular(64, 8, 32., 16000., Scale::Mel);
+        let flat = vec![2f64; 64];
+        assert!(b
+            .bucket_weighted(&flat.into())
+            .iter()
+            .all(|&x| (x - 2.).abs() < 1e-12));
+
+        // a single bin is shared between the two buckets whose triangles cover it
+        let centers = b.center_frequencies();
+        let mut spectrum = vec![0f64; 64];
+        let bin = ((centers[3] + centers[4]) / 2. / 250.) as usize;
+        spectrum[bin] = 1.;
+        let out = b.bucket_weighted(&spectrum.clone().into()).clone();
+        assert!(out[3] > 0. && out[4] > 0.);
+        assert_eq!(out.iter().filter(|&&x| x > 0.).count(), 2);
+
+        // while bucket puts it in only one
+        let mut blocky = Bucketer::with_scale(64, 8, 32., 16000., Scale::Mel);
+        let out = blocky.bucket(&spectrum.into());
+        assert_eq!(out.iter().filter(|&&x| x > 0.).count(), 1);
+    }
+
+    #[test]
+    fn more_buckets_than_bins() {
+        let mut b = Bucketer::new(8, 16, 32., 22000.);
+        let out = b.bucket(&vec![1f64; 8].into());
+        assert!(out.iter().all(|x| !x.is_nan()), "{:?}", out);
+        assert_eq!(out.iter().sum::<f64>(), 8.);
+        let empty: Vec<bool> = out.iter().map(|&x| x == 0.).collect();
+        assert!(empty.contains(&true));
+
+        // empty buckets are at the floor, and the others at the power of their bins
+        b.set_mode(BucketMode::PowerDb { floor_db: -100. });
+        let out = b.bucket(&vec![1f64; 8].into());
+        for (&x, &e) in out.iter().zip(empty.iter()) {
+            if e {
+                assert_eq!(x, -100.);
+            } else {
+                assert!(x >= 0., "{:?}", out);
+            }
+        }
+
+        let mut b = Bucketer::with_triangular(8, 16, 32., 22000., Scale::Log2);
+        let out = b.bucket_weighted(&vec![1f64; 8].into());
+        assert!(out.iter().all(|x| !x.is_nan()), "{:?}", out);
+    }
+
+    #[test]
+    fn aggregation() {
+        // buckets cover bins [0, 1), [1, 2), [2, 4), [4, 16)
+        let mut b = Bucketer::new(16, 4, 32., 16000.);
+        let input = (0u8..16).map(f64::from).collect();
+        assert_eq!(b.get_aggregation(), Aggregation::Mean);
+        b.set_aggregation(Aggregation::Max);
+        assert_eq!(b.bucket(&input), &vec![0f64, 1., 3., 15.]);
+
+        b.set_aggregation(Aggregation::Sum);
+        assert_eq!(b.bucket(&input), &vec![0f64, 1., 5., 114.]);
+
+        b.set_aggregation(Aggregation::Rms);
+        let out = b.bucket(&input);
+        assert_eq!(out[1], 1.);
+        assert!((out[2] - 6.5f64.sqrt()).abs() < 1e-12);
+
+        // the dB output mode always adds the power of the bins
+        b.set_mode(BucketMode::PowerDb { floor_db: -100. });
+        assert_eq!(b.bucket(&input)[2], 10. * 5f64.log10());
+    }
+
+    #[test]
+    fn chroma() {
+        let (fft_size, sample_rate) = (8192, 44100.);
+        let bin_width = sample_rate / fft_size as f64;
+        let mut b = Bucketer::chroma(fft_size, sample_rate, 440.);
+        assert_eq!(b.center_frequencies()[9], 440.);
+
+        // A in every octave, and E, goes to its pitch class
+        let mut spectrum = vec![0f64; fft_size / 2];
+        for &f in &[220., 440., 880., 1760., 659.26] {
+            spectrum[(f / bin_width).round() as usize] = 1.;
+            assert_eq!(b.bucket_for_frequency(f), if f == 659.26 { 4 } else { 9 });
+        }
+        // frequencies either side of the boundary between A and A#, whose bins both fold into A,
+        // map to their own pitch classes
+        let below = 110. * 2f64.powf(0.45 / 12.);
+        let above = 110. * 2f64.powf(0.55 / 12.);
+        assert_eq!(b.bucket_for_frequency(below), 9);
+        assert_eq!(b.bucket_for_frequency(above), 10);
+        // bins which are too low are dropped
+        spectrum[2] = 1.;
+        let out = b.bucket(&spectrum.clone().into());
+        assert_eq!(out.len(), 12);
+        assert_eq!(out[9], 4.);
+        assert_eq!(out[4], 1.);
+        assert_eq!(out.iter().sum::<f64>(), 5.);
+
+        // tuned down to 432Hz, A is at 432Hz
+        let mut b = Bucketer::chroma(fft_size, sample_rate, 432.);
+        let mut spectrum = vec![0f64; fft_size / 2];
+        spectrum[(432. / bin_width).round() as usize] = 1.;
+        assert_eq!(b.bucket(&spectrum.into())[9], 1.);
+    }
+
+    #[test]
+    fn weighting() {
+        // standard values at the given frequencies, in dB
+        for &(f, a, c) in &[(100., -19.1, -0.3), (1000., 0., 0.), (10000., -2.5, -4.4)] {
+            assert!((Weighting::A.db(f) - a).abs() < 0.1, "A({})", f);
+            assert!((Weighting::C.db(f) - c).abs() < 0.1, "C({})", f);
+        }
+
+        let mut b = Bucketer::new(64, 8, 32., 16000.);
+        assert_eq!(b.get_weighting(), Weighting::None);
+        let flat = vec![1f64; 64];
+        let plain = b.bucket(&flat.clone().into()).clone();
+        b.set_weighting(Weighting::A);
+        let weighted = b.bucket(&flat.clone().into()).clone();
+        let centers = b.center_frequencies();
+        for i in 0..8 {
+            let gain = 10f64.powf(Weighting::A.db(centers[i]) / 20.);
+            assert!((weighted[i] - plain[i] * gain).abs() < 1e-12);
+        }
+        // the lowest bucket, centered at 125Hz, is attenuated by around 16dB
+        assert!(weighted[0] < 0.2 * plain[0]);
+
+        // decibels are offset rather than scaled
+        b.set_mode(BucketMode::PowerDb { floor_db: -100. });
+        let out = b.bucket(&flat.into());
+        assert!((out[3] - Weighting::A.db(centers[3])).abs() < 1e-12);
+    }
 }
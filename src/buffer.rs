@@ -4,6 +4,7 @@ pub struct WindowBuffer {
     buffer: Vec<f64>,
     index: usize,
     capacity: usize,
+    filled: usize,
 }
 
 impl WindowBuffer {
@@ -12,10 +13,11 @@ impl WindowBuffer {
             buffer: vec![0f64; capacity],
             index: 0,
             capacity,
+            filled: 0,
         }
     }
 
-    pub fn push(&mut self, x: &Vec<f64>) {
+    pub fn push(&mut self, x: &[f64]) {
         if x.len() > self.capacity {
             panic!("cannot push size greater than capacity");
         }
@@ -38,15 +40,66 @@ impl WindowBuffer {
         }
 
         self.index = (self.index + x.len()) % self.capacity;
+        self.filled = (self.filled + x.len()).min(self.capacity);
+    }
+
+    /// scale_tail multiplies the `gains.len()` most recently pushed elements by `gains`, oldest
+    /// first.
+    pub fn scale_tail(&mut self, gains: &[f64]) {
+        if gains.len() > self.capacity {
+            panic!("cannot scale size greater than capacity");
+        }
+        let start = self.index + self.capacity - gains.len();
+        for (i, g) in gains.iter().enumerate() {
+            self.buffer[(start + i) % self.capacity] *= g;
+        }
+    }
+
+    /// clear empties the buffer, as if nothing had been pushed.
+    pub fn clear(&mut self) {
+        for x in self.buffer.iter_mut() {
+            *x = 0.;
+        }
+        self.index = 0;
+        self.filled = 0;
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// is_primed returns whether at least `size` elements have been pushed, so that `get(size)`
+    /// contains no initial zero padding.
+    pub fn is_primed(&self, size: usize) -> bool {
+        self.filled >= size
+    }
+
+    /// get_into_delayed is like `get_into`, but the elements written to `out` end `delay`
+    /// elements before the most recently pushed one.
+    pub fn get_into_delayed(&self, out: &mut [f64], delay: usize) {
+        if out.len() + delay > self.capacity {
+            panic!("cannot get size plus delay greater than capacity");
+        }
+        let start = (self.index + 2 * self.capacity - delay - out.len()) % self.capacity;
+        for (j, o) in out.iter_mut().enumerate() {
+            *o = self.buffer[(start + j) % self.capacity];
+        }
     }
 
     pub fn get(&self, size: usize) -> Vec<f64> {
+        let mut out = vec![0f64; size];
+        self.get_into(&mut out);
+        out
+    }
+
+    /// get_into is like `get`, but writes the `out.len()` most recently pushed elements into
+    /// `out` rather than allocating.
+    pub fn get_into(&self, out: &mut [f64]) {
+        let size = out.len();
         if size > self.capacity {
             panic!("cannot get size greater than capacity");
         }
 
-        let mut out = vec![0f64; size];
-
         let s = self.index as i32 - size as i32;
         let (st, en, wrap) = if s < 0 {
             (self.capacity as i32 + s, self.capacity as i32, true)
@@ -63,8 +116,6 @@ impl WindowBuffer {
                 out[i + os as usize] = self.buffer[i];
             }
         }
-
-        out
     }
 }
 
@@ -80,7 +131,41 @@ mod tests {
         b.push(&v);
         assert_eq!(b.get(4), v);
 
-        b.push(&vec![69., 420.]);
+        b.push(&[69., 420.]);
         assert_eq!(b.get(4), vec![2., 3., 69., 420.]);
     }
+
+    #[test]
+    fn is_primed() {
+        let mut b = WindowBuffer::new(4);
+        assert!(!b.is_primed(1));
+
+        b.push(&[1., 2., 3.]);
+        assert!(b.is_primed(3));
+        assert!(!b.is_primed(4));
+
+        b.push(&[4., 5.]);
+        assert!(b.is_primed(4));
+    }
+
+    #[test]
+    fn scale_tail() {
+        let mut b = WindowBuffer::new(4);
+        b.push(&[1., 2., 3.]);
+        b.push(&[4., 5.]);
+        b.scale_tail(&[0.5, 0., 2.]);
+        assert_eq!(b.get(4), vec![2., 1.5, 0., 10.]);
+    }
+
+    #[test]
+    fn get_into_delayed() {
+        let mut b = WindowBuffer::new(5);
+        b.push(&[1., 2., 3., 4.]);
+        b.push(&[5., 6., 7.]);
+        let mut out = [0f64; 3];
+        b.get_into_delayed(&mut out, 0);
+        assert_eq!(out, [5., 6., 7.]);
+        b.get_into_delayed(&mut out, 2);
+        assert_eq!(out, [3., 4., 5.]);
+    }
 }
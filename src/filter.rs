@@ -1,6 +1,13 @@
+use std::f64::consts::PI;
+
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+#[cfg(feature = "simd")]
+use wide::f64x4;
+
+use crate::util::lerp;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct FilterParams {
     pub a: f64,
     pub b: f64,
@@ -20,6 +27,13 @@ impl FilterParams {
         f
     }
 
+    /// from_seconds creates params whose time constant is `tau_seconds` regardless of the rate
+    /// at which frames are filtered, given as `frame_rate` frames per second. The response to a
+    /// step reaches half way in tau.
+    pub fn from_seconds(tau_seconds: f64, gain: f64, frame_rate: f64) -> FilterParams {
+        FilterParams::new(tau_seconds * frame_rate, gain)
+    }
+
     pub fn set_coefficients(&mut self, tau: f64, gain: f64) {
         self.tau = tau;
         self.gain = gain;
@@ -37,6 +51,18 @@ impl FilterParams {
     pub fn get_coefficients(&self) -> Vec<f64> {
         vec![self.tau, self.gain]
     }
+
+    /// lerp interpolates the tau and gain of two sets of params and recomputes the coefficients.
+    pub fn lerp(a: &Self, b: &Self, t: f64) -> Self {
+        FilterParams::new(lerp(a.tau, b.tau, t), lerp(a.gain, b.gain, t))
+    }
+}
+
+impl Default for FilterParams {
+    /// The default params pass the input through unchanged.
+    fn default() -> Self {
+        FilterParams::new(0., 1.)
+    }
 }
 
 impl Serialize for FilterParams {
@@ -85,34 +111,68 @@ impl Filter {
         }
     }
 
-    // fn process_simd(&self, input: &Vec<f64>) {
-    //     unsafe {
-    //         let a: v128 = std::mem::transmute([self.params.a; 4]);
-    //         let b: v128 = std::mem::transmute([self.params.b; 4]);
-    //     }
+    pub fn process(&mut self, input: &Vec<f64>, params: &FilterParams) {
+        #[cfg(feature = "simd")]
+        self.process_simd(input, params);
+        #[cfg(not(feature = "simd"))]
+        self.process_scalar(input, params);
+    }
+
+    #[cfg_attr(feature = "simd", allow(dead_code))]
+    fn process_scalar(&mut self, input: &[f64], params: &FilterParams) {
+        for (v, x) in self.values.iter_mut().zip(input) {
+            *v = params.a * x + params.b * *v;
+        }
+    }
+
+    // process_simd filters 4 values at a time, then the remainder one at a time. The products
+    // and sums are not fused, so the result is identical to process_scalar.
+    #[cfg(feature = "simd")]
+    fn process_simd(&mut self, input: &[f64], params: &FilterParams) {
+        let (a, b) = (f64x4::splat(params.a), f64x4::splat(params.b));
+        let n = input.len().min(self.values.len());
+        let (input, tail) = input[..n].split_at(n / 4 * 4);
+        let (values, tail_values) = self.values[..n].split_at_mut(n / 4 * 4);
+        for (v, x) in values.chunks_exact_mut(4).zip(input.chunks_exact(4)) {
+            let x = f64x4::from([x[0], x[1], x[2], x[3]]);
+            let y = a * x + b * f64x4::from([v[0], v[1], v[2], v[3]]);
+            v.copy_from_slice(&y.to_array());
+        }
+        for (v, x) in tail_values.iter_mut().zip(tail) {
+            *v = params.a * x + params.b * *v;
+        }
+    }
 
-    //     let mut i = 0;
-    //     let len = input.len() as i32 - 4;
-    //     while i < len {
-    //         unsafe {
-    //             let v_in: v128 = std::mem::transmute(input[i..i + 4]);
-    //             let v_val: v128 = std::mem::transmute(self.values[i..i + 4]);
-    //             let v = f64x4_add(f64x4_mul(a, v_in), f64x4_mul(b, v_val));
-    //             self.values[i..i + 4] = std::mem::transmute(v);
-    //         }
-    //         i += 4;
-    //     }
-    // }
+    /// reset clears the filter state, as if no input had been processed.
+    pub fn reset(&mut self) {
+        self.reset_to(0.);
+    }
 
-    pub fn process(&mut self, input: &Vec<f64>, params: &FilterParams) {
-        for i in 0..input.len() {
-            self.values[i] = params.a * input[i] + params.b * self.values[i];
+    /// reset_to sets every filter's state to `value`, e.g. to start from a known level after
+    /// switching inputs.
+    pub fn reset_to(&mut self, value: f64) {
+        for x in self.values.iter_mut() {
+            *x = value;
         }
     }
 
     pub fn get_values(&self) -> &Vec<f64> {
         &self.values
     }
+
+    /// set_values restores the filter state, e.g. as captured by `get_values`. The values must
+    /// have one entry for each filter.
+    pub fn set_values(&mut self, values: &[f64]) -> Result<()> {
+        if values.len() != self.values.len() {
+            return Err(anyhow!(
+                "got {} filter values, expected {}",
+                values.len(),
+                self.values.len()
+            ));
+        }
+        self.values.copy_from_slice(values);
+        Ok(())
+    }
 }
 
 /// BiasedFilter uses separate coefficients depending on whether the input is greater or
@@ -139,6 +199,18 @@ impl BiasedFilter {
         }
     }
 
+    /// reset clears the filter state, as if no input had been processed.
+    pub fn reset(&mut self) {
+        self.reset_to(0.);
+    }
+
+    /// reset_to sets every filter's state to `value`.
+    pub fn reset_to(&mut self, value: f64) {
+        for x in self.values.iter_mut() {
+            *x = value;
+        }
+    }
+
     pub fn get_values(&self) -> &Vec<f64> {
         &self.values
     }
@@ -146,4 +218,237 @@ impl BiasedFilter {
     pub fn get_values_mut(&mut self) -> &mut Vec<f64> {
         &mut self.values
     }
+
+    /// set_values restores the filter state, e.g. as captured by `get_values`. The values must
+    /// have one entry for each filter.
+    pub fn set_values(&mut self, values: &[f64]) -> Result<()> {
+        if values.len() != self.values.len() {
+            return Err(anyhow!(
+                "got {} filter values, expected {}",
+                values.len(),
+                self.values.len()
+            ));
+        }
+        self.values.copy_from_slice(values);
+        Ok(())
+    }
+}
+
+/// BiquadParams are the coefficients of a second order IIR filter, normalized so that a0 is 1.
+/// The constructors are the usual RBJ cookbook designs for a `cutoff` frequency and `q`, for
+/// input arriving at `rate`, in the same units, e.g. Hz and samples per second, or frames per
+/// second for filters applied to features.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct BiquadParams {
+    pub b0: f64,
+    pub b1: f64,
+    pub b2: f64,
+    pub a1: f64,
+    pub a2: f64,
+}
+
+impl BiquadParams {
+    // from_a0 normalizes the coefficients of a design by a0
+    fn from_a0(b0: f64, b1: f64, b2: f64, a0: f64, a1: f64, a2: f64) -> BiquadParams {
+        BiquadParams {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+
+    // cos and alpha of the cutoff, shared by all of the designs
+    fn omega(cutoff: f64, q: f64, rate: f64) -> (f64, f64) {
+        let w = 2. * PI * cutoff / rate;
+        (w.cos(), w.sin() / (2. * q))
+    }
+
+    pub fn lowpass(cutoff: f64, q: f64, rate: f64) -> BiquadParams {
+        let (cos, alpha) = Self::omega(cutoff, q, rate);
+        let b = (1. - cos) / 2.;
+        Self::from_a0(b, 2. * b, b, 1. + alpha, -2. * cos, 1. - alpha)
+    }
+
+    pub fn highpass(cutoff: f64, q: f64, rate: f64) -> BiquadParams {
+        let (cos, alpha) = Self::omega(cutoff, q, rate);
+        let b = (1. + cos) / 2.;
+        Self::from_a0(b, -2. * b, b, 1. + alpha, -2. * cos, 1. - alpha)
+    }
+
+    /// bandpass has a peak gain of 1 at `cutoff`.
+    pub fn bandpass(cutoff: f64, q: f64, rate: f64) -> BiquadParams {
+        let (cos, alpha) = Self::omega(cutoff, q, rate);
+        Self::from_a0(alpha, 0., -alpha, 1. + alpha, -2. * cos, 1. - alpha)
+    }
+
+    pub fn notch(cutoff: f64, q: f64, rate: f64) -> BiquadParams {
+        let (cos, alpha) = Self::omega(cutoff, q, rate);
+        Self::from_a0(1., -2. * cos, 1., 1. + alpha, -2. * cos, 1. - alpha)
+    }
+}
+
+impl Default for BiquadParams {
+    /// The default params pass the input through unchanged.
+    fn default() -> Self {
+        BiquadParams {
+            b0: 1.,
+            b1: 0.,
+            b2: 0.,
+            a1: 0.,
+            a2: 0.,
+        }
+    }
+}
+
+/// Biquad implements a bank of N second order IIR filters that process a frame in parallel,
+/// for a steeper response than the single pole `Filter`.
+pub struct Biquad {
+    values: Vec<f64>,
+    // the two delay elements of each filter, in transposed direct form II
+    z1: Vec<f64>,
+    z2: Vec<f64>,
+}
+
+impl Biquad {
+    pub fn new(size: usize) -> Biquad {
+        Biquad {
+            values: vec![0f64; size],
+            z1: vec![0f64; size],
+            z2: vec![0f64; size],
+        }
+    }
+
+    pub fn process(&mut self, input: &[f64], params: &BiquadParams) {
+        let p = params;
+        let state = self.z1.iter_mut().zip(self.z2.iter_mut());
+        for ((&x, y), (z1, z2)) in input.iter().zip(self.values.iter_mut()).zip(state) {
+            *y = p.b0 * x + *z1;
+            *z1 = p.b1 * x - p.a1 * *y + *z2;
+            *z2 = p.b2 * x - p.a2 * *y;
+        }
+    }
+
+    /// reset clears the filter state, as if no input had been processed.
+    pub fn reset(&mut self) {
+        for v in [&mut self.values, &mut self.z1, &mut self.z2] {
+            for x in v.iter_mut() {
+                *x = 0.;
+            }
+        }
+    }
+
+    pub fn get_values(&self) -> &Vec<f64> {
+        &self.values
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BiasedFilter, Biquad, BiquadParams, Filter, FilterParams};
+    use std::f64::consts::PI;
+
+    #[test]
+    fn reset() {
+        let params = FilterParams::new(4., 1.);
+        let input = vec![1., 2., 3.];
+
+        let mut f = Filter::new(3);
+        f.process(&input, &params);
+        assert!(f.get_values().iter().all(|&x| x > 0.));
+        f.reset();
+        assert_eq!(f.get_values(), &vec![0.; 3]);
+        f.reset_to(0.5);
+        assert_eq!(f.get_values(), &vec![0.5; 3]);
+
+        let mut b = BiasedFilter::new(3);
+        b.process(&input, (&params, &params));
+        assert!(b.get_values().iter().all(|&x| x > 0.));
+        b.reset();
+        assert_eq!(b.get_values(), &vec![0.; 3]);
+        b.reset_to(-1.);
+        assert_eq!(b.get_values(), &vec![-1.; 3]);
+    }
+
+    #[test]
+    fn biquad() {
+        // the steady state amplitude of each filter for a dc input and for sines at the given
+        // frequencies, with a rate of 1000, from the rms of whole periods of the output
+        let amplitude = |params: BiquadParams, f: f64| {
+            let mut filter = Biquad::new(1);
+            let mut power = 0f64;
+            for i in 0..4000 {
+                let x = if f == 0. {
+                    1.
+                } else {
+                    (2. * PI * f * i as f64 / 1000.).sin()
+                };
+                filter.process(&[x], &params);
+                if i >= 3000 {
+                    power += filter.get_values()[0].powi(2) / 1000.;
+                }
+            }
+            if f == 0. {
+                power.sqrt()
+            } else {
+                (2. * power).sqrt()
+            }
+        };
+        let q = 0.5f64.sqrt();
+
+        let lowpass = BiquadParams::lowpass(50., q, 1000.);
+        assert!((amplitude(lowpass, 0.) - 1.).abs() < 1e-6);
+        assert!((amplitude(lowpass, 50.) - 0.5f64.sqrt()).abs() < 0.01);
+        // 12dB per octave
+        assert!(amplitude(lowpass, 250.) < 0.04);
+
+        let highpass = BiquadParams::highpass(50., q, 1000.);
+        assert!(amplitude(highpass, 0.) < 1e-6);
+        assert!((amplitude(highpass, 250.) - 1.).abs() < 0.01);
+
+        let bandpass = BiquadParams::bandpass(100., 2., 1000.);
+        assert!((amplitude(bandpass, 100.) - 1.).abs() < 0.01);
+        assert!(amplitude(bandpass, 0.) < 1e-6);
+        assert!(amplitude(bandpass, 250.) < 0.25);
+
+        let notch = BiquadParams::notch(100., 2., 1000.);
+        assert!(amplitude(notch, 100.) < 0.01);
+        assert!((amplitude(notch, 0.) - 1.).abs() < 1e-6);
+
+        assert!((amplitude(BiquadParams::default(), 0.) - 1.).abs() < 1e-12);
+    }
+
+    #[test]
+    fn process_matches_scalar() {
+        // 11 values covers both the lanes and the remainder of the simd version
+        let params = FilterParams::new(3., 0.9);
+        let mut f = Filter::new(11);
+        let mut expected = vec![0f64; 11];
+        for frame in 0..5 {
+            let input: Vec<f64> = (0..11).map(|i| ((i * 7 + frame) % 5) as f64 - 2.).collect();
+            f.process(&input, &params);
+            for (v, x) in expected.iter_mut().zip(input.iter()) {
+                *v = params.a * x + params.b * *v;
+            }
+            assert_eq!(f.get_values(), &expected);
+        }
+    }
+
+    #[test]
+    fn from_seconds() {
+        // the step response after the same time is the same at either frame rate
+        let step = |frame_rate: f64, seconds: f64| {
+            let params = FilterParams::from_seconds(0.2, 1., frame_rate);
+            let mut f = Filter::new(1);
+            for _ in 0..(seconds * frame_rate).round() as usize {
+                f.process(&vec![1.], &params);
+            }
+            f.get_values()[0]
+        };
+        assert!((step(30., 0.2) - 0.5).abs() < 1e-12);
+        for &seconds in &[0.1, 0.2, 1.] {
+            assert!((step(30., seconds) - step(120., seconds)).abs() < 1e-9);
+        }
+    }
 }
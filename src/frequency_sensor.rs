@@ -4,36 +4,216 @@
 // #![cfg(target_arch = "wasm32")]
 use core::fmt::Write;
 
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize, Serializer};
 
 use crate::filter::{BiasedFilter, Filter, FilterParams};
 use crate::gain_control::{
     GainController, Params as GainControllerParams, State as GainControllerState,
 };
+use crate::util::{lerp, lerp_usize, pick};
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+// input is clamped to at least -120dB before being converted to decibels
+const DB_FLOOR: f64 = 1e-6;
+
+/// ScaleMode selects how the `scales` of the features are computed. `Deviation` tracks each
+/// amplitude's filtered deviation from 1. `Variance` tracks an exponential moving mean and
+/// variance of each amplitude and sets the scale to 1 / (scale_k * std).
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+pub enum ScaleMode {
+    #[default]
+    Deviation,
+    Variance,
+}
+
+/// PreemphasisCurve is the shape of the per-bucket multiplier applied by preemphasis, which
+/// peaks at `preemphasis`. `Linear` rises from 1 at the first bucket to `preemphasis` at the
+/// last. `Tent` and `Parabola` peak at bucket `center` and fall to 1 at `width` buckets away
+/// from it, linearly or quadratically.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+pub enum PreemphasisCurve {
+    #[default]
+    Linear,
+    Tent {
+        center: f64,
+        width: f64,
+    },
+    Parabola {
+        center: f64,
+        width: f64,
+    },
+}
+
+impl PreemphasisCurve {
+    // lerp interpolates the center and width of two curves of the same shape
+    fn lerp(a: &Self, b: &Self, t: f64) -> Self {
+        use PreemphasisCurve::*;
+        match (*a, *b) {
+            (
+                Tent {
+                    center: c0,
+                    width: w0,
+                },
+                Tent {
+                    center: c1,
+                    width: w1,
+                },
+            ) => Tent {
+                center: lerp(c0, c1, t),
+                width: lerp(w0, w1, t),
+            },
+            (
+                Parabola {
+                    center: c0,
+                    width: w0,
+                },
+                Parabola {
+                    center: c1,
+                    width: w1,
+                },
+            ) => Parabola {
+                center: lerp(c0, c1, t),
+                width: lerp(w0, w1, t),
+            },
+            (a, b) => pick(a, b, t),
+        }
+    }
+
+    // gain returns the multiplier for bucket i of size, given the peak multiplier
+    fn gain(&self, i: usize, size: usize, peak: f64) -> f64 {
+        let i = i as f64;
+        let shape = match *self {
+            PreemphasisCurve::Linear => i / size as f64,
+            PreemphasisCurve::Tent { center, width } => 1. - (i - center).abs() / width,
+            PreemphasisCurve::Parabola { center, width } => 1. - ((i - center) / width).powi(2),
+        };
+        1. + (peak - 1.) * shape.max(0.)
+    }
+}
+
+/// SoftKnee is a compressor curve for the final amplitudes. Values below `threshold - width / 2`
+/// pass unchanged, values above `threshold + width / 2` rise at `1 / ratio` of their input
+/// slope, and the slope changes smoothly in between.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct SoftKnee {
+    pub threshold: f64,
+    pub width: f64,
+    pub ratio: f64,
+}
+
+impl SoftKnee {
+    pub fn apply(&self, x: f64) -> f64 {
+        let over = x - self.threshold;
+        let slope = 1. / self.ratio - 1.;
+        if 2. * over <= -self.width {
+            x
+        } else if 2. * over < self.width {
+            let k = over + self.width / 2.;
+            x + slope * k * k / (2. * self.width)
+        } else {
+            x + slope * over
+        }
+    }
+
+    fn lerp(a: Option<Self>, b: Option<Self>, t: f64) -> Option<Self> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(SoftKnee {
+                threshold: lerp(a.threshold, b.threshold, t),
+                width: lerp(a.width, b.width, t),
+                ratio: lerp(a.ratio, b.ratio, t),
+            }),
+            _ => pick(a, b, t),
+        }
+    }
+}
+
+/// Fields missing from serialized params take their value from `FrequencySensorParams::default`.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(default)]
 pub struct FrequencySensorParams {
     pub preemphasis: f64,
+    pub preemphasis_curve: PreemphasisCurve,
     pub diff_gain: f64,
     pub amp_scale: f64,
     pub amp_offset: f64,
     pub sync: f64,
+    /// sync_weighted scales the energy each bucket pushes into its neighbors during sync by
+    /// its current amplitude, so that strong buckets spread further than weak ones.
+    pub sync_weighted: bool,
+    /// sync_wrap makes the first and last buckets neighbors during sync, so that energy is
+    /// continuous around a ring of buckets, e.g. on a circular display.
+    pub sync_wrap: bool,
+    /// sync_radius is how many buckets away on either side each bucket is coupled to during
+    /// sync. The coupling to a bucket d buckets away is scaled by 1 / d.
+    pub sync_radius: usize,
     pub drag: f64,
+    /// energy_min and energy_max bound the accumulated energy, so that sustained input can't
+    /// grow it without limit.
+    pub energy_min: f64,
+    pub energy_max: f64,
     pub amp_filter: FilterParams,
+    /// filter_in_db applies `amp_filter` to the input in decibels rather than linearly, which
+    /// weights loud and quiet input more evenly. Toggling it while running causes a brief jump
+    /// since the filter state is kept in whichever domain was last used.
+    pub filter_in_db: bool,
+    /// amp_feedback is added to the filtered amplitude. Its gain must be in [-1, 0], which is
+    /// checked by `validate`: a positive gain would add the input to itself, and a gain below -1
+    /// makes the feedback diverge. As `FilterParams` scales both coefficients by the gain, a
+    /// negative gain also flips the sign of the filter's state on every frame, which adds a
+    /// ripple at half the frame rate. It is 0 by default, so that the amplitude decays
+    /// monotonically after an impulse.
     pub amp_feedback: FilterParams,
     pub diff_filter: FilterParams,
     pub diff_feedback: FilterParams,
     pub pos_scale_filter: FilterParams,
     pub neg_scale_filter: FilterParams,
+    pub scale_mode: ScaleMode,
+    /// scale_variance_filter is the moving average filter for the mean and mean square of the
+    /// amplitude in `ScaleMode::Variance`.
+    pub scale_variance_filter: FilterParams,
+    /// scale_k is the number of standard deviations mapped to a scaled value of 1 in
+    /// `ScaleMode::Variance`.
+    pub scale_k: f64,
+    /// scale_gamma shapes each scaled amplitude, `scales[i] * amplitude[i]`, by raising it to
+    /// this power. Below 1 it compresses the range toward "everything equalized"; above 1 it
+    /// expands it so that loud buckets stay loud. 1 leaves the scales as they are.
+    pub scale_gamma: f64,
+    /// peak_attack and peak_release filter the rising and falling input of the attack
+    /// amplitudes.
+    pub peak_attack: FilterParams,
+    pub peak_release: FilterParams,
+    /// display_filter smooths the returned amplitudes as the last step of processing. Unlike
+    /// `amp_filter` it has no effect on the diff, energy or scales.
+    pub display_filter: FilterParams,
+    /// noise_window is the number of frames over which the minimum of each bucket's filtered
+    /// level is tracked to estimate its noise floor.
+    pub noise_window: usize,
+    /// soft_knee compresses the amplitudes and attack amplitudes after the effects stage, so
+    /// that peaks roll off rather than clip on saturating outputs. The energy is not compressed
+    /// since it accumulates from frame to frame.
+    pub soft_knee: Option<SoftKnee>,
 
     pub gain_control: GainControllerParams,
+    /// gain_enabled can be turned off for sources that are already normalized, in which case
+    /// the gain controller is skipped and the gain is treated as unity.
+    pub gain_enabled: bool,
+    /// preemphasis_enabled, filters_enabled, effects_enabled, sync_enabled and scaling_enabled
+    /// turn the remaining stages of `FrequencySensor::process` on or off, to find which stage is
+    /// responsible for an artifact. With the filters off, the later stages see the unfiltered
+    /// input.
+    pub preemphasis_enabled: bool,
+    pub filters_enabled: bool,
+    pub effects_enabled: bool,
+    pub sync_enabled: bool,
+    pub scaling_enabled: bool,
 }
 
 impl Default for FrequencySensorParams {
     fn default() -> Self {
         Self {
             amp_filter: FilterParams::new(8., 1.),
-            amp_feedback: FilterParams::new(200., -1.),
+            filter_in_db: false,
+            amp_feedback: FilterParams::new(200., 0.),
             diff_filter: FilterParams::new(16., 1.),
             diff_feedback: FilterParams::new(100., -0.05),
             gain_control: GainControllerParams {
@@ -41,17 +221,149 @@ impl Default for FrequencySensorParams {
                 ki: 0.1,
                 kp: 0.1,
                 kd: 0.1,
-                filter_params: FilterParams::new(1720., 1.),
+                attack_filter: FilterParams::new(1720., 1.),
+                release_filter: FilterParams::new(1720., 1.),
+                ..Default::default()
             },
             amp_offset: 0.,
             preemphasis: 2.,
+            preemphasis_curve: PreemphasisCurve::default(),
             sync: 0.001,
+            sync_weighted: false,
+            sync_wrap: false,
+            sync_radius: 1,
+            energy_min: -1e6,
+            energy_max: 1e6,
             amp_scale: 1.,
             diff_gain: 1.,
             drag: 0.001,
             pos_scale_filter: FilterParams::new(100., 1.),
             neg_scale_filter: FilterParams::new(1000., 1.),
+            scale_mode: ScaleMode::default(),
+            scale_variance_filter: FilterParams::new(100., 1.),
+            scale_k: 2.,
+            scale_gamma: 1.,
+            peak_attack: FilterParams::new(0., 1.),
+            peak_release: FilterParams::new(100., 1.),
+            display_filter: FilterParams::default(),
+            noise_window: 400,
+            soft_knee: None,
+            gain_enabled: true,
+            preemphasis_enabled: true,
+            filters_enabled: true,
+            effects_enabled: true,
+            sync_enabled: true,
+            scaling_enabled: true,
+        }
+    }
+}
+
+impl FrequencySensorParams {
+    /// lerp interpolates between two sets of params, see `AnalyzerParams::lerp`.
+    pub fn lerp(a: &Self, b: &Self, t: f64) -> Self {
+        let f = |a: &FilterParams, b: &FilterParams| FilterParams::lerp(a, b, t);
+        Self {
+            preemphasis: lerp(a.preemphasis, b.preemphasis, t),
+            preemphasis_curve: PreemphasisCurve::lerp(
+                &a.preemphasis_curve,
+                &b.preemphasis_curve,
+                t,
+            ),
+            diff_gain: lerp(a.diff_gain, b.diff_gain, t),
+            amp_scale: lerp(a.amp_scale, b.amp_scale, t),
+            amp_offset: lerp(a.amp_offset, b.amp_offset, t),
+            sync: lerp(a.sync, b.sync, t),
+            sync_weighted: pick(a.sync_weighted, b.sync_weighted, t),
+            sync_wrap: pick(a.sync_wrap, b.sync_wrap, t),
+            sync_radius: lerp_usize(a.sync_radius, b.sync_radius, t),
+            energy_min: lerp(a.energy_min, b.energy_min, t),
+            energy_max: lerp(a.energy_max, b.energy_max, t),
+            drag: lerp(a.drag, b.drag, t),
+            amp_filter: f(&a.amp_filter, &b.amp_filter),
+            filter_in_db: pick(a.filter_in_db, b.filter_in_db, t),
+            amp_feedback: f(&a.amp_feedback, &b.amp_feedback),
+            diff_filter: f(&a.diff_filter, &b.diff_filter),
+            diff_feedback: f(&a.diff_feedback, &b.diff_feedback),
+            pos_scale_filter: f(&a.pos_scale_filter, &b.pos_scale_filter),
+            neg_scale_filter: f(&a.neg_scale_filter, &b.neg_scale_filter),
+            scale_mode: pick(a.scale_mode, b.scale_mode, t),
+            scale_variance_filter: f(&a.scale_variance_filter, &b.scale_variance_filter),
+            scale_k: lerp(a.scale_k, b.scale_k, t),
+            scale_gamma: lerp(a.scale_gamma, b.scale_gamma, t),
+            peak_attack: f(&a.peak_attack, &b.peak_attack),
+            peak_release: f(&a.peak_release, &b.peak_release),
+            display_filter: f(&a.display_filter, &b.display_filter),
+            noise_window: lerp_usize(a.noise_window, b.noise_window, t),
+            soft_knee: SoftKnee::lerp(a.soft_knee, b.soft_knee, t),
+            gain_control: GainControllerParams::lerp(&a.gain_control, &b.gain_control, t),
+            gain_enabled: pick(a.gain_enabled, b.gain_enabled, t),
+            preemphasis_enabled: pick(a.preemphasis_enabled, b.preemphasis_enabled, t),
+            filters_enabled: pick(a.filters_enabled, b.filters_enabled, t),
+            effects_enabled: pick(a.effects_enabled, b.effects_enabled, t),
+            sync_enabled: pick(a.sync_enabled, b.sync_enabled, t),
+            scaling_enabled: pick(a.scaling_enabled, b.scaling_enabled, t),
+        }
+    }
+
+    /// reset_filters restores every `FilterParams` field to its default, leaving the rest of the
+    /// params untouched.
+    pub fn reset_filters(&mut self) {
+        let d = Self::default();
+        self.amp_filter = d.amp_filter;
+        self.amp_feedback = d.amp_feedback;
+        self.diff_filter = d.diff_filter;
+        self.diff_feedback = d.diff_feedback;
+        self.pos_scale_filter = d.pos_scale_filter;
+        self.neg_scale_filter = d.neg_scale_filter;
+        self.scale_variance_filter = d.scale_variance_filter;
+        self.peak_attack = d.peak_attack;
+        self.peak_release = d.peak_release;
+        self.display_filter = d.display_filter;
+    }
+
+    /// reset_gain restores the gain controller params and `gain_enabled` to their defaults.
+    pub fn reset_gain(&mut self) {
+        let d = Self::default();
+        self.gain_control = d.gain_control;
+        self.gain_enabled = d.gain_enabled;
+    }
+
+    /// reset_stages turns every stage of processing back on.
+    pub fn reset_stages(&mut self) {
+        let d = Self::default();
+        self.preemphasis_enabled = d.preemphasis_enabled;
+        self.filters_enabled = d.filters_enabled;
+        self.effects_enabled = d.effects_enabled;
+        self.sync_enabled = d.sync_enabled;
+        self.scaling_enabled = d.scaling_enabled;
+    }
+
+    /// validate returns an error if the params would make the sensor unstable.
+    pub fn validate(&self) -> Result<()> {
+        self.gain_control.validate()?;
+        let gain = self.amp_feedback.get_coefficients()[1];
+        if !(-1. ..=0.).contains(&gain) {
+            return Err(anyhow!(
+                "amp_feedback gain must be in [-1, 0], got {}",
+                gain
+            ));
+        }
+        if self.energy_min > self.energy_max {
+            return Err(anyhow!(
+                "energy_min {} is greater than energy_max {}",
+                self.energy_min,
+                self.energy_max
+            ));
         }
+        if let Some(knee) = self.soft_knee {
+            if knee.width < 0. || knee.ratio < 1. {
+                return Err(anyhow!(
+                    "soft_knee needs width >= 0 and ratio >= 1, got {:?}",
+                    knee
+                ));
+            }
+        }
+        Ok(())
     }
 }
 
@@ -61,12 +373,20 @@ impl Default for FrequencySensorParams {
 /// keep scale[i] * amplitude[n][i] mostly in the range of (-1, 1).
 /// `diff` is the lowpass-filtered magnitude of the difference of each new frame minus the prior.
 /// `energy` is the accumulation of diff over time.
+/// `attack_amplitudes` follows rising input instantly and decays slowly, like a peak meter.
+/// `noise_floor` is the minimum of each bucket's filtered level over the recent noise window.
+/// `metadata` is an optional per-bucket value, such as a color or weight, which is passed
+/// through processing unchanged.
 #[derive(Clone, Debug, Default)]
 pub struct Features {
     amplitudes: Vec<Vec<f64>>,
+    attack_amplitudes: Vec<f64>,
+    level: Vec<f64>,
+    noise_floor: Vec<f64>,
     scales: Vec<f64>,
     diff: Vec<f64>,
     energy: Vec<f64>,
+    metadata: Vec<f64>,
 
     size: usize,
     length: usize,
@@ -83,6 +403,7 @@ impl Serialize for Features {
         #[derive(Serialize)]
         pub struct Features<'a> {
             amplitudes: &'a Vec<f64>,
+            attack_amplitudes: &'a Vec<f64>,
             scales: &'a Vec<f64>,
             diff: &'a Vec<f64>,
             energy: &'a Vec<f64>,
@@ -90,6 +411,7 @@ impl Serialize for Features {
         }
         let f = Features {
             amplitudes: self.get_amplitudes(0),
+            attack_amplitudes: self.get_attack_amplitudes(),
             scales: self.get_scales(),
             diff: self.get_diff(),
             energy: self.get_energy(),
@@ -100,11 +422,42 @@ impl Serialize for Features {
 }
 
 impl Features {
+    /// serialize_history serializes the features like their `Serialize` impl, which only
+    /// includes the most recent amplitudes, but with `amplitudes` holding every frame of
+    /// history from oldest to newest. It can be used with `#[serde(serialize_with)]`.
+    pub fn serialize_history<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        #[derive(Serialize)]
+        pub struct Features<'a> {
+            amplitudes: Vec<&'a Vec<f64>>,
+            attack_amplitudes: &'a Vec<f64>,
+            scales: &'a Vec<f64>,
+            diff: &'a Vec<f64>,
+            energy: &'a Vec<f64>,
+            frame_count: usize,
+        }
+        let f = Features {
+            amplitudes: self.iter_amplitudes().collect(),
+            attack_amplitudes: self.get_attack_amplitudes(),
+            scales: self.get_scales(),
+            diff: self.get_diff(),
+            energy: self.get_energy(),
+            frame_count: self.frame_count,
+        };
+        f.serialize(serializer)
+    }
+
     pub fn new(size: usize, length: usize) -> Self {
         Self {
             size,
             length,
             amplitudes: (0..length).map(|_| vec![0f64; size]).collect(),
+            attack_amplitudes: vec![0f64; size],
+            level: vec![0f64; size],
+            noise_floor: vec![0f64; size],
+            metadata: Vec::new(),
             scales: vec![0f64; size],
             diff: vec![0f64; size],
             energy: vec![0f64; size],
@@ -117,6 +470,25 @@ impl Features {
         (self.size, self.length)
     }
 
+    /// resize_length changes the number of frames of amplitude history, keeping the most recent
+    /// `min(old, new)` frames. Any new frames are zeroed. It panics if `new_length` is 0, as the
+    /// current frame is always kept.
+    pub fn resize_length(&mut self, new_length: usize) {
+        if new_length == 0 {
+            panic!("history length must be positive");
+        }
+        let new_index = self.frame_count % new_length;
+        let mut amplitudes: Vec<Vec<f64>> =
+            (0..new_length).map(|_| vec![0f64; self.size]).collect();
+        for i in 0..self.length.min(new_length) {
+            let j = (new_index + new_length - i) % new_length;
+            amplitudes[j].copy_from_slice(self.get_amplitudes(i));
+        }
+        self.amplitudes = amplitudes;
+        self.length = new_length;
+        self.index = new_index;
+    }
+
     fn increment_index(&mut self) {
         self.frame_count += 1;
         self.index = self.frame_count % self.length;
@@ -134,11 +506,67 @@ impl Features {
         &self.amplitudes[self.current_index(i)]
     }
 
+    /// iter_amplitudes iterates over every frame of amplitude history, from oldest to newest.
+    pub fn iter_amplitudes(&self) -> impl Iterator<Item = &Vec<f64>> {
+        self.get_amplitudes_range(0, self.length)
+    }
+
+    /// get_amplitudes_range iterates over `count` frames of amplitude history from oldest to
+    /// newest, ending with `get_amplitudes(start)`. `start + count` must not exceed the
+    /// history length.
+    pub fn get_amplitudes_range(
+        &self,
+        start: usize,
+        count: usize,
+    ) -> impl Iterator<Item = &Vec<f64>> {
+        if start + count > self.length {
+            panic!(
+                "range of {} frames from {} exceeds history length {}",
+                count, start, self.length
+            );
+        }
+        (start..start + count)
+            .rev()
+            .map(move |i| self.get_amplitudes(i))
+    }
+
     fn get_amplitudes_mut(&mut self, i: usize) -> &mut Vec<f64> {
         let i = self.current_index(i);
         &mut self.amplitudes[i]
     }
 
+    /// amplitude_delta returns the change in amplitude of each bucket between the two most
+    /// recent frames. Unlike `diff`, it is not filtered. It is all zeros when fewer than two
+    /// frames of history are kept.
+    pub fn amplitude_delta(&self) -> Vec<f64> {
+        if self.length < 2 {
+            return vec![0f64; self.size];
+        }
+        self.get_amplitudes(0)
+            .iter()
+            .zip(self.get_amplitudes(1).iter())
+            .map(|(a, b)| a - b)
+            .collect()
+    }
+
+    pub fn get_attack_amplitudes(&self) -> &Vec<f64> {
+        &self.attack_amplitudes
+    }
+
+    pub fn get_noise_floor(&self) -> &Vec<f64> {
+        &self.noise_floor
+    }
+
+    /// snr returns an estimate of each bucket's signal to noise ratio in dB, as the ratio of
+    /// its filtered level to its noise floor.
+    pub fn snr(&self) -> Vec<f64> {
+        self.level
+            .iter()
+            .zip(self.noise_floor.iter())
+            .map(|(l, n)| 20. * (l.max(1e-9) / n.max(1e-9)).log10())
+            .collect()
+    }
+
     pub fn get_scales(&self) -> &Vec<f64> {
         &self.scales
     }
@@ -151,6 +579,25 @@ impl Features {
         &self.energy
     }
 
+    /// get_metadata returns the per-bucket metadata, which is empty unless it has been set.
+    pub fn get_metadata(&self) -> &Vec<f64> {
+        &self.metadata
+    }
+
+    /// set_metadata attaches per-bucket metadata to the features. It must either be empty or
+    /// have one value per bucket.
+    pub fn set_metadata(&mut self, metadata: Vec<f64>) -> Result<()> {
+        if !metadata.is_empty() && metadata.len() != self.size {
+            return Err(anyhow!(
+                "metadata length {} does not match size {}",
+                metadata.len(),
+                self.size
+            ));
+        }
+        self.metadata = metadata;
+        Ok(())
+    }
+
     pub fn get_frame_count(&self) -> usize {
         self.frame_count
     }
@@ -158,9 +605,150 @@ impl Features {
     pub fn get_index(&self) -> usize {
         self.index
     }
+
+    /// contiguous_history writes the whole amplitude history into `out`, which must hold
+    /// `length * size` values, one row of `size` buckets per frame from oldest to newest. This is
+    /// the layout expected for uploading the history directly to a texture.
+    pub fn contiguous_history(&self, out: &mut [f32]) {
+        if out.len() != self.length * self.size {
+            panic!(
+                "history buffer has length {}, expected {}",
+                out.len(),
+                self.length * self.size
+            );
+        }
+        for (row, frame) in out.chunks_exact_mut(self.size).zip(self.iter_amplitudes()) {
+            for (o, &x) in row.iter_mut().zip(frame.iter()) {
+                *o = x as f32;
+            }
+        }
+    }
+
+    /// summary returns a compact summary of the current frame, with the amplitudes averaged
+    /// down into `bands` bands of adjacent buckets. `centers` is the center frequency of each
+    /// bucket, as returned by `Bucketer::center_frequencies`, and is used for the centroid.
+    /// The beat flag is left unset, see `Analyzer::summary`.
+    pub fn summary(&self, bands: usize, centers: &[f64]) -> Summary {
+        if bands == 0 || bands > self.size {
+            panic!("summary bands must be in 1..={}, got {}", self.size, bands);
+        }
+        if centers.len() != self.size {
+            panic!(
+                "got {} center frequencies, expected {}",
+                centers.len(),
+                self.size
+            );
+        }
+        let amplitudes = self.get_amplitudes(0);
+        let total: f64 = amplitudes.iter().sum();
+        let centroid = if total > 0. {
+            amplitudes
+                .iter()
+                .zip(centers.iter())
+                .map(|(a, c)| a * c)
+                .sum::<f64>()
+                / total
+        } else {
+            0.
+        };
+        Summary {
+            energy: self.energy.iter().sum::<f64>() as f32,
+            centroid: centroid as f32,
+            beat: false,
+            bands: (0..bands)
+                .map(|i| {
+                    let band = &amplitudes[i * self.size / bands..(i + 1) * self.size / bands];
+                    (band.iter().sum::<f64>() / band.len() as f64) as f32
+                })
+                .collect(),
+            frame_count: self.frame_count,
+        }
+    }
+
+    /// to_f32 returns the current frame of features as `f32`.
+    pub fn to_f32(&self) -> FeaturesF32 {
+        let mut out = FeaturesF32::default();
+        self.write_f32(&mut out);
+        out
+    }
+
+    /// write_f32 writes the current frame of features into `out` as `f32`, reusing its buffers.
+    pub fn write_f32(&self, out: &mut FeaturesF32) {
+        fn convert(out: &mut Vec<f32>, v: &[f64]) {
+            out.clear();
+            out.extend(v.iter().map(|&x| x as f32));
+        }
+        convert(&mut out.amplitudes, self.get_amplitudes(0));
+        convert(&mut out.attack_amplitudes, &self.attack_amplitudes);
+        convert(&mut out.scales, &self.scales);
+        convert(&mut out.diff, &self.diff);
+        convert(&mut out.energy, &self.energy);
+        out.frame_count = self.frame_count;
+    }
+
+    /// reverse_buckets reverses the bucket order of every feature vector, so that the highest
+    /// frequency bucket comes first.
+    pub fn reverse_buckets(&mut self) {
+        for amp in self.amplitudes.iter_mut() {
+            amp.reverse();
+        }
+        self.attack_amplitudes.reverse();
+        self.level.reverse();
+        self.noise_floor.reverse();
+        self.metadata.reverse();
+        self.scales.reverse();
+        self.diff.reverse();
+        self.energy.reverse();
+    }
+}
+
+/// FeaturesF32 holds the current frame of `Features` as `f32`, for renderers and GPU uploads.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct FeaturesF32 {
+    pub amplitudes: Vec<f32>,
+    pub attack_amplitudes: Vec<f32>,
+    pub scales: Vec<f32>,
+    pub diff: Vec<f32>,
+    pub energy: Vec<f32>,
+    pub frame_count: usize,
+}
+
+impl FeaturesF32 {
+    pub fn reverse_buckets(&mut self) {
+        self.amplitudes.reverse();
+        self.attack_amplitudes.reverse();
+        self.scales.reverse();
+        self.diff.reverse();
+        self.energy.reverse();
+    }
+}
+
+/// Summary is a small per-frame digest of `Features`, for sending to many displays over the
+/// network. `energy` is the total energy of all buckets, `centroid` is the amplitude-weighted
+/// mean frequency in Hz, and `bands` are the amplitudes averaged into a few bars.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub energy: f32,
+    pub centroid: f32,
+    pub beat: bool,
+    pub bands: Vec<f32>,
+    pub frame_count: usize,
 }
 
 /// FrequencySensor maintains a `Features` vector that tracks incoming frames.
+///
+/// It can be used on its own with any front end which produces a vector of bucket values:
+///
+/// ```
+/// use audio::frequency_sensor::{FrequencySensor, FrequencySensorParams};
+///
+/// let params = FrequencySensorParams::default();
+/// let mut fs = FrequencySensor::new(4, 2);
+/// for _ in 0..10 {
+///     fs.process_buckets(&mut vec![0.1, 0.2, 0.3, 0.4], &params).unwrap();
+/// }
+/// assert_eq!(fs.get_features().get_amplitudes(0).len(), 4);
+/// ```
 pub struct FrequencySensor {
     features: Features,
 
@@ -170,14 +758,29 @@ pub struct FrequencySensor {
     diff_filter: Filter,
     diff_feedback: Filter,
     scale_filter: BiasedFilter,
+    // moving mean and mean square of the amplitude for ScaleMode::Variance
+    amp_mean: Filter,
+    amp_sq_mean: Filter,
+    peak_filter: BiasedFilter,
+    display_filter: Filter,
 
     size: usize,
 
+    // minimum statistics over the current and previous noise windows
+    noise_min: Vec<f64>,
+    noise_min_prev: Vec<f64>,
+    noise_frames: usize,
+
     scale_buffer: Vec<f64>,
     diff_buffer: Vec<f64>,
+    // linear output of the amp filter
+    amp_values: Vec<f64>,
 }
 
-#[derive(Debug, Serialize, Default, Clone)]
+/// State is the internal state of a `FrequencySensor` that carries over from one frame to the
+/// next: the filter and gain state, the noise floor minimums and the accumulated energy. It can
+/// be saved with `get_state` and restored with `load_state`.
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq)]
 pub struct State {
     gain_controller: GainControllerState,
     amp_filter: Vec<f64>,
@@ -185,6 +788,16 @@ pub struct State {
     diff_filter: Vec<f64>,
     diff_feedback: Vec<f64>,
     scale_filter: Vec<f64>,
+    amp_mean: Vec<f64>,
+    amp_sq_mean: Vec<f64>,
+    peak_filter: Vec<f64>,
+    display_filter: Vec<f64>,
+    // the minimums of windows without any frames yet are infinite, and are stored as f64::MAX
+    // so that the state can be written as JSON
+    noise_min: Vec<f64>,
+    noise_min_prev: Vec<f64>,
+    noise_frames: usize,
+    energy: Vec<f64>,
 }
 
 impl FrequencySensor {
@@ -198,25 +811,98 @@ impl FrequencySensor {
             diff_filter: Filter::new(size),
             diff_feedback: Filter::new(size),
             scale_filter: BiasedFilter::new(size),
+            amp_mean: Filter::new(size),
+            amp_sq_mean: Filter::new(size),
+            peak_filter: BiasedFilter::new(size),
+            display_filter: Filter::new(size),
+            noise_min: vec![f64::INFINITY; size],
+            noise_min_prev: vec![f64::INFINITY; size],
+            noise_frames: 0,
             scale_buffer: vec![0f64; size],
             diff_buffer: vec![0f64; size],
+            amp_values: vec![0f64; size],
+        }
+    }
+
+    /// set_history_length changes the number of frames of amplitude history kept in the
+    /// features without disturbing any other state. It panics if `length` is 0, see
+    /// `Features::resize_length`.
+    pub fn set_history_length(&mut self, length: usize) {
+        self.features.resize_length(length);
+    }
+
+    /// set_metadata attaches per-bucket metadata to the features. See `Features::set_metadata`.
+    pub fn set_metadata(&mut self, metadata: Vec<f64>) -> Result<()> {
+        self.features.set_metadata(metadata)
+    }
+
+    /// reset_energy clears the accumulated energy, leaving the filters, the value scales and the
+    /// amplitude history untouched.
+    pub fn reset_energy(&mut self) {
+        for x in self.features.energy.iter_mut() {
+            *x = 0.;
         }
     }
 
+    /// reset clears all of the sensor's state, including the features and their history, the
+    /// filters and the gain controller, as if it had just been created. The size, history
+    /// length and metadata are kept.
+    pub fn reset(&mut self) {
+        let (size, length) = self.features.get_size();
+        let metadata = std::mem::take(&mut self.features.metadata);
+        *self = FrequencySensor::new(size, length);
+        self.features.metadata = metadata;
+    }
+
     /// get_features returns the current features vector
     pub fn get_features(&self) -> &Features {
         &self.features
     }
 
+    /// process_buckets updates the features from a vector of bucket values, such as the output
+    /// of a `Bucketer`, returning an error if its length is not the sensor's size. The buckets
+    /// are modified in place by preemphasis and gain control.
+    pub fn process_buckets(
+        &mut self,
+        buckets: &mut Vec<f64>,
+        params: &FrequencySensorParams,
+    ) -> Result<()> {
+        if buckets.len() != self.size {
+            return Err(anyhow!(
+                "got {} buckets, expected {}",
+                buckets.len(),
+                self.size
+            ));
+        }
+        self.process(buckets, params);
+        Ok(())
+    }
+
     /// process updates the features vector
     pub fn process(&mut self, input: &mut Vec<f64>, params: &FrequencySensorParams) {
-        self.features.frame_count += 1;
-        self.apply_preemphasis(input, params);
-        self.apply_gain_control(input, params);
-        self.apply_filters(input, params);
-        self.apply_effects(params);
-        self.apply_sync(params);
-        self.apply_value_scaling(params);
+        if params.preemphasis_enabled {
+            self.apply_preemphasis(input, params);
+        }
+        if params.gain_enabled {
+            self.apply_gain_control(input, params);
+        }
+        if params.filters_enabled {
+            self.apply_filters(input, params);
+        } else {
+            self.amp_values.copy_from_slice(input);
+        }
+        self.apply_noise_floor(params);
+        self.features.increment_index();
+        if params.effects_enabled {
+            self.apply_effects(params);
+        }
+        if params.sync_enabled {
+            self.apply_sync(params);
+        }
+        if params.scaling_enabled {
+            self.apply_value_scaling(params);
+        }
+        self.apply_display_filter(params);
     }
 
     pub fn get_state(&self) -> State {
@@ -227,7 +913,62 @@ impl FrequencySensor {
             diff_filter: self.diff_filter.get_values().clone(),
             diff_feedback: self.diff_feedback.get_values().clone(),
             scale_filter: self.scale_filter.get_values().clone(),
+            amp_mean: self.amp_mean.get_values().clone(),
+            amp_sq_mean: self.amp_sq_mean.get_values().clone(),
+            peak_filter: self.peak_filter.get_values().clone(),
+            display_filter: self.display_filter.get_values().clone(),
+            noise_min: self.noise_min.iter().map(|x| x.min(f64::MAX)).collect(),
+            noise_min_prev: self
+                .noise_min_prev
+                .iter()
+                .map(|x| x.min(f64::MAX))
+                .collect(),
+            noise_frames: self.noise_frames,
+            energy: self.features.energy.clone(),
+        }
+    }
+
+    /// load_state restores the state captured by `get_state`, e.g. to resume analysis after a
+    /// restart. Nothing is changed if the state is for a different size. The amplitude history
+    /// is not part of the state, so a resumed sensor's features match from its next frame on.
+    pub fn load_state(&mut self, state: &State) -> Result<()> {
+        let filters = [
+            &state.amp_filter,
+            &state.amp_feedback,
+            &state.diff_filter,
+            &state.diff_feedback,
+            &state.scale_filter,
+            &state.amp_mean,
+            &state.amp_sq_mean,
+            &state.peak_filter,
+            &state.display_filter,
+            &state.noise_min,
+            &state.noise_min_prev,
+            &state.energy,
+        ];
+        if let Some(v) = filters.iter().find(|v| v.len() != self.size) {
+            return Err(anyhow!(
+                "state has {} filter values, expected {}",
+                v.len(),
+                self.size
+            ));
         }
+        self.gain_controller.load_state(&state.gain_controller)?;
+        self.amp_filter.set_values(&state.amp_filter)?;
+        self.amp_feedback.set_values(&state.amp_feedback)?;
+        self.diff_filter.set_values(&state.diff_filter)?;
+        self.diff_feedback.set_values(&state.diff_feedback)?;
+        self.scale_filter.set_values(&state.scale_filter)?;
+        self.amp_mean.set_values(&state.amp_mean)?;
+        self.amp_sq_mean.set_values(&state.amp_sq_mean)?;
+        self.peak_filter.set_values(&state.peak_filter)?;
+        self.display_filter.set_values(&state.display_filter)?;
+        let unbounded = |x: &f64| if *x == f64::MAX { f64::INFINITY } else { *x };
+        self.noise_min = state.noise_min.iter().map(unbounded).collect();
+        self.noise_min_prev = state.noise_min_prev.iter().map(unbounded).collect();
+        self.noise_frames = state.noise_frames;
+        self.features.energy.copy_from_slice(&state.energy);
+        Ok(())
     }
 
     pub fn write_debug<W>(&self, w: &mut W) -> core::fmt::Result
@@ -275,9 +1016,10 @@ impl FrequencySensor {
     }
 
     fn apply_preemphasis(&mut self, input: &mut Vec<f64>, params: &FrequencySensorParams) {
-        let incr = (params.preemphasis - 1.) / self.size as f64;
-        for i in 0..self.size {
-            input[i] *= 1. + i as f64 * incr;
+        for (i, x) in input.iter_mut().enumerate() {
+            *x *= params
+                .preemphasis_curve
+                .gain(i, self.size, params.preemphasis);
         }
     }
 
@@ -288,12 +1030,26 @@ impl FrequencySensor {
     fn apply_filters(&mut self, input: &Vec<f64>, params: &FrequencySensorParams) {
         self.diff_buffer.copy_from_slice(input);
 
-        self.amp_filter.process(input, &params.amp_filter);
+        if params.filter_in_db {
+            for (v, x) in self.amp_values.iter_mut().zip(input.iter()) {
+                *v = 20. * x.max(DB_FLOOR).log10();
+            }
+            self.amp_filter
+                .process(&self.amp_values, &params.amp_filter);
+            for (v, db) in self.amp_values.iter_mut().zip(self.amp_filter.get_values()) {
+                *v = (10f64).powf(db / 20.);
+            }
+        } else {
+            self.amp_filter.process(input, &params.amp_filter);
+            self.amp_values
+                .copy_from_slice(self.amp_filter.get_values());
+        }
         self.amp_feedback.process(input, &params.amp_feedback);
+        self.peak_filter
+            .process(input, (&params.peak_release, &params.peak_attack));
 
-        let amp_filter = self.amp_filter.get_values();
         for i in 0..self.size {
-            self.diff_buffer[i] = amp_filter[i] - self.diff_buffer[i];
+            self.diff_buffer[i] = self.amp_values[i] - self.diff_buffer[i];
         }
 
         self.diff_filter
@@ -302,18 +1058,50 @@ impl FrequencySensor {
             .process(&self.diff_buffer, &params.diff_feedback);
     }
 
+    fn apply_noise_floor(&mut self, params: &FrequencySensorParams) {
+        for (i, &l) in self.amp_values.iter().enumerate() {
+            self.noise_min[i] = self.noise_min[i].min(l);
+            self.features.level[i] = l;
+            self.features.noise_floor[i] = self.noise_min[i].min(self.noise_min_prev[i]);
+        }
+
+        self.noise_frames += 1;
+        if self.noise_frames >= params.noise_window {
+            self.noise_frames = 0;
+            std::mem::swap(&mut self.noise_min, &mut self.noise_min_prev);
+            for x in self.noise_min.iter_mut() {
+                *x = f64::INFINITY;
+            }
+        }
+    }
+
     fn apply_effects(&mut self, params: &FrequencySensorParams) {
         let dg = params.diff_gain;
         let ag = params.amp_scale;
         let ao = params.amp_offset;
 
-        self.features.increment_index();
         {
             let amp = self.features.get_amplitudes_mut(0);
-            let amp_filter = self.amp_filter.get_values();
+            let amp_feedback = self.amp_feedback.get_values();
+            // the combined amp path is clamped so that feedback can't make it negative
+            for i in 0..self.size {
+                amp[i] = ao + ag * (self.amp_values[i] + amp_feedback[i]).max(0.);
+            }
+        }
+        {
+            let peak_filter = self.peak_filter.get_values();
             let amp_feedback = self.amp_feedback.get_values();
             for i in 0..self.size {
-                amp[i] = ao + ag * (amp_filter[i] + amp_feedback[i]);
+                self.features.attack_amplitudes[i] =
+                    ao + ag * (peak_filter[i] + amp_feedback[i]).max(0.);
+            }
+        }
+        if let Some(knee) = params.soft_knee {
+            for x in self.features.get_amplitudes_mut(0).iter_mut() {
+                *x = knee.apply(*x);
+            }
+            for x in self.features.attack_amplitudes.iter_mut() {
+                *x = knee.apply(*x);
             }
         }
         let diff_filter = self.diff_filter.get_values();
@@ -321,23 +1109,42 @@ impl FrequencySensor {
         for i in 0..self.size {
             let diff = dg * (diff_filter[i] + diff_feedback[i]);
             self.features.diff[i] = diff;
-            self.features.energy[i] = self.features.energy[i] + diff - params.drag;
+            self.features.energy[i] = (self.features.energy[i] + diff - params.drag)
+                .clamp(params.energy_min, params.energy_max);
         }
     }
 
     fn apply_sync(&mut self, params: &FrequencySensorParams) {
-        let energy = &mut self.features.energy;
+        let features = &mut self.features;
+        let amp = &features.amplitudes[features.current_index(0)];
+        let energy = &mut features.energy;
         let size_f = self.size as f64;
         let mean = energy.iter().sum::<f64>() / size_f;
 
+        let weight = |j: usize| if params.sync_weighted { amp[j] } else { 1. };
         let sync = params.sync;
-        for i in 0..self.size {
-            if i > 0 {
-                energy[i] += sync * FrequencySensor::signed_square_diff(energy[i - 1], energy[i]);
-            }
-
-            if i < (self.size - 1) {
-                energy[i] += sync * FrequencySensor::signed_square_diff(energy[i + 1], energy[i]);
+        let size = self.size;
+        for i in 0..size {
+            for d in 1..=params.sync_radius {
+                let prev = if i >= d {
+                    Some(i - d)
+                } else if params.sync_wrap {
+                    Some((i + size - d % size) % size)
+                } else {
+                    None
+                };
+                let next = if i + d < size {
+                    Some(i + d)
+                } else if params.sync_wrap {
+                    Some((i + d) % size)
+                } else {
+                    None
+                };
+                let k = sync / d as f64;
+                for j in prev.into_iter().chain(next).filter(|&j| j != i) {
+                    energy[i] +=
+                        k * weight(j) * FrequencySensor::signed_square_diff(energy[j], energy[i]);
+                }
             }
 
             energy[i] += (sync / size_f) * FrequencySensor::signed_square_diff(mean, energy[i]);
@@ -345,10 +1152,22 @@ impl FrequencySensor {
     }
 
     fn apply_value_scaling(&mut self, params: &FrequencySensorParams) {
+        if params.scale_mode == ScaleMode::Variance {
+            self.apply_variance_scaling(params);
+        } else {
+            self.apply_deviation_scaling(params);
+        }
+        self.apply_scale_gamma(params);
+    }
+
+    fn apply_deviation_scaling(&mut self, params: &FrequencySensorParams) {
         let amp = self.features.get_amplitudes(0);
 
+        // the scales before scale_gamma, which is applied after the filter
         for i in 0..self.size {
-            self.scale_buffer[i] = (self.features.scales[i] * (amp[i] - 1.)).abs();
+            let vsh = self.scale_filter.get_values()[i];
+            let scale = if vsh > 0. { 1. / vsh } else { 0. };
+            self.scale_buffer[i] = (scale * (amp[i] - 1.)).abs();
         }
 
         self.scale_filter.process(
@@ -368,6 +1187,45 @@ impl FrequencySensor {
         }
     }
 
+    fn apply_variance_scaling(&mut self, params: &FrequencySensorParams) {
+        let amp = self.features.get_amplitudes(0);
+        for (s, a) in self.scale_buffer.iter_mut().zip(amp.iter()) {
+            *s = a * a;
+        }
+        self.amp_mean.process(amp, &params.scale_variance_filter);
+        self.amp_sq_mean
+            .process(&self.scale_buffer, &params.scale_variance_filter);
+
+        let mean = self.amp_mean.get_values();
+        let sq_mean = self.amp_sq_mean.get_values();
+        for ((s, m), sq) in self.features.scales.iter_mut().zip(mean).zip(sq_mean) {
+            let std = (sq - m * m).max(0.).sqrt();
+            *s = 1. / (params.scale_k * std).max(0.001);
+        }
+    }
+
+    fn apply_scale_gamma(&mut self, params: &FrequencySensorParams) {
+        if params.scale_gamma == 1. {
+            return;
+        }
+        let features = &mut self.features;
+        let amp = &features.amplitudes[features.current_index(0)];
+        for (s, &a) in features.scales.iter_mut().zip(amp.iter()) {
+            let v = *s * a;
+            if v != 0. {
+                *s = v.signum() * v.abs().powf(params.scale_gamma) / a;
+            }
+        }
+    }
+
+    fn apply_display_filter(&mut self, params: &FrequencySensorParams) {
+        self.display_filter
+            .process(self.features.get_amplitudes(0), &params.display_filter);
+        self.features
+            .get_amplitudes_mut(0)
+            .copy_from_slice(self.display_filter.get_values());
+    }
+
     fn signed_square_diff(a: f64, b: f64) -> f64 {
         let diff = a - b;
         diff.signum() * diff * diff
@@ -402,3 +1260,655 @@ impl<'a> Display for VecFmt<'a> {
         write!(f, "[ {} ]", comma_separated)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Features, FrequencySensor, FrequencySensorParams, PreemphasisCurve, ScaleMode, SoftKnee,
+    };
+    use crate::filter::FilterParams;
+
+    #[test]
+    fn gain_disabled() {
+        let params = FrequencySensorParams {
+            gain_enabled: false,
+            ..Default::default()
+        };
+        let mut fs = FrequencySensor::new(4, 2);
+        for k in 0..100 {
+            let level = (k % 7) as f64;
+            let mut input = vec![level; 4];
+            fs.process(&mut input, &params);
+            // only the preemphasis of 1 + i * (2 - 1) / 4 is applied
+            assert_eq!(input, vec![level, 1.25 * level, 1.5 * level, 1.75 * level]);
+        }
+    }
+
+    #[test]
+    fn set_history_length() {
+        let mut fs = FrequencySensor::new(4, 4);
+        for k in 0..7 {
+            fs.process(&mut vec![k as f64; 4], &Default::default());
+        }
+        let history: Vec<Vec<f64>> = (0..4)
+            .map(|i| fs.get_features().get_amplitudes(i).clone())
+            .collect();
+
+        fs.set_history_length(2);
+        assert_eq!(fs.get_features().get_size(), (4, 2));
+        for (i, amp) in history.iter().enumerate().take(2) {
+            assert_eq!(fs.get_features().get_amplitudes(i), amp);
+        }
+
+        fs.set_history_length(6);
+        assert_eq!(fs.get_features().get_size(), (4, 6));
+        for (i, amp) in history.iter().enumerate().take(2) {
+            assert_eq!(fs.get_features().get_amplitudes(i), amp);
+        }
+        for i in 2..6 {
+            assert_eq!(fs.get_features().get_amplitudes(i), &vec![0f64; 4]);
+        }
+
+        fs.process(&mut vec![1.; 4], &Default::default());
+        assert_eq!(fs.get_features().get_amplitudes(1), &history[0]);
+        assert_eq!(fs.get_features().get_amplitudes(2), &history[1]);
+    }
+
+    #[test]
+    fn snr() {
+        let params = FrequencySensorParams {
+            gain_enabled: false,
+            preemphasis: 1.,
+            noise_window: 200,
+            ..Default::default()
+        };
+        let mut seed = 1u64;
+        let mut noise = move || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            0.1 + 0.05 * (seed >> 11) as f64 / (1u64 << 53) as f64
+        };
+
+        // the first window includes the filters rising from zero
+        let mut fs = FrequencySensor::new(2, 2);
+        for _ in 0..500 {
+            fs.process(&mut vec![noise(), noise()], &params);
+        }
+        for _ in 0..100 {
+            fs.process(&mut vec![1. + noise(), noise()], &params);
+        }
+
+        let snr = fs.get_features().snr();
+        assert!(snr[0] > 15., "{:?}", snr);
+        assert!(snr[1].abs() < 3., "{:?}", snr);
+    }
+
+    #[test]
+    fn filter_in_db() {
+        let mut params = FrequencySensorParams {
+            gain_enabled: false,
+            preemphasis: 1.,
+            amp_filter: FilterParams::new(50., 1.),
+            ..Default::default()
+        };
+        let settle = |params: &FrequencySensorParams| {
+            let mut fs = FrequencySensor::new(1, 2);
+            for k in 0..2000 {
+                let level = if k % 2 == 0 { 1. } else { 100. };
+                fs.process(&mut vec![level], params);
+            }
+            let a = fs.amp_values[0];
+            fs.process(&mut vec![1.], params);
+            (a * fs.amp_values[0]).sqrt()
+        };
+
+        let linear = settle(&params);
+        assert!((linear - 50.5).abs() < 5., "{}", linear);
+
+        params.filter_in_db = true;
+        let db = settle(&params);
+        assert!((db - 10.).abs() < 0.5, "{}", db);
+    }
+
+    #[test]
+    fn attack_amplitudes() {
+        let params = FrequencySensorParams {
+            gain_enabled: false,
+            preemphasis: 1.,
+            ..Default::default()
+        };
+        let mut fs = FrequencySensor::new(4, 2);
+        for _ in 0..10 {
+            fs.process(&mut vec![0.; 4], &params);
+        }
+
+        fs.process(&mut vec![1.; 4], &params);
+        let feat = fs.get_features();
+        for i in 0..4 {
+            assert!(feat.get_attack_amplitudes()[i] > 0.9);
+            assert!(feat.get_amplitudes(0)[i] < 0.5);
+        }
+
+        let peak = feat.get_attack_amplitudes()[0];
+        for _ in 0..10 {
+            fs.process(&mut vec![0.; 4], &params);
+        }
+        let decayed = fs.get_features().get_attack_amplitudes()[0];
+        assert!(decayed < peak);
+        assert!(decayed > 0.8 * peak);
+    }
+
+    #[test]
+    fn amplitude_delta() {
+        let mut f = Features::new(3, 4);
+        f.increment_index();
+        f.get_amplitudes_mut(0).copy_from_slice(&[1., 2., 3.]);
+        f.increment_index();
+        f.get_amplitudes_mut(0).copy_from_slice(&[2., 0., 3.5]);
+        assert_eq!(f.amplitude_delta(), vec![1., -2., 0.5]);
+
+        let mut f = Features::new(3, 1);
+        f.increment_index();
+        f.get_amplitudes_mut(0).copy_from_slice(&[1., 2., 3.]);
+        assert_eq!(f.amplitude_delta(), vec![0., 0., 0.]);
+    }
+
+    #[test]
+    fn amp_feedback_stability() {
+        let params = FrequencySensorParams::default();
+        assert!(params.validate().is_ok());
+
+        let mut fs = FrequencySensor::new(4, 2);
+        for k in 0..200 {
+            let level = if (k / 20) % 2 == 0 { 1. } else { 0. };
+            fs.process(&mut vec![level; 4], &params);
+            assert!(fs.get_features().get_amplitudes(0).iter().all(|&a| a >= 0.));
+        }
+
+        let params = FrequencySensorParams {
+            amp_feedback: FilterParams::new(200., -4.),
+            ..Default::default()
+        };
+        assert!(params.validate().is_err());
+
+        let params = FrequencySensorParams {
+            amp_feedback: FilterParams::new(200., 0.5),
+            ..Default::default()
+        };
+        assert!(params.validate().is_err());
+    }
+
+    #[test]
+    fn sync_disabled() {
+        let run = |sync_enabled: bool| {
+            let params = FrequencySensorParams {
+                sync_enabled,
+                ..Default::default()
+            };
+            let mut fs = FrequencySensor::new(4, 2);
+            for k in 0..50 {
+                let level = (k % 5) as f64;
+                fs.process(&mut vec![level, 0., 0., 0.], &params);
+            }
+            fs.get_features().get_energy().clone()
+        };
+
+        // without sync the silent buckets only feel the drag
+        let drag = FrequencySensorParams::default().drag;
+        let energy = run(false);
+        for &e in &energy[1..] {
+            assert!((e + 50. * drag).abs() < 1e-12);
+        }
+
+        let energy = run(true);
+        assert!((energy[1] + 50. * drag).abs() > 1e-6);
+    }
+
+    #[test]
+    fn variance_scaling() {
+        let params = FrequencySensorParams {
+            scale_mode: ScaleMode::Variance,
+            preemphasis_enabled: false,
+            gain_enabled: false,
+            filters_enabled: false,
+            ..Default::default()
+        };
+        let mut fs = FrequencySensor::new(2, 2);
+        let mut scales = Vec::new();
+        for k in 0..2000 {
+            // bucket 0 swings much further than bucket 1
+            let input = if k % 2 == 0 {
+                vec![0., 0.9]
+            } else {
+                vec![2., 1.1]
+            };
+            fs.process(&mut input.clone(), &params);
+            scales.push(fs.get_features().get_scales().clone());
+        }
+
+        let last = &scales[scales.len() - 1];
+        assert!(last[0] < last[1], "{:?}", last);
+
+        let prev = &scales[scales.len() - 3];
+        for (s, p) in last.iter().zip(prev.iter()) {
+            assert!((s - p).abs() < 1e-6 * s);
+        }
+    }
+
+    #[test]
+    fn preemphasis_curve() {
+        let size = 9;
+        let tent = PreemphasisCurve::Tent {
+            center: 4.,
+            width: 4.,
+        };
+        let parabola = PreemphasisCurve::Parabola {
+            center: 4.,
+            width: 4.,
+        };
+        for curve in [tent, parabola].iter() {
+            let gains: Vec<f64> = (0..size).map(|i| curve.gain(i, size, 3.)).collect();
+            assert_eq!(gains[4], 3.);
+            assert_eq!(gains[0], 1.);
+            assert_eq!(gains[8], 1.);
+            assert!(gains[3] > gains[1] && gains[5] > gains[7]);
+        }
+
+        // the linear curve matches the original ramp
+        let linear = PreemphasisCurve::Linear;
+        for i in 0..size {
+            let expected = 1. + i as f64 * (2. - 1.) / size as f64;
+            assert!((linear.gain(i, size, 2.) - expected).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn process_buckets() {
+        let params = FrequencySensorParams {
+            gain_enabled: false,
+            preemphasis_enabled: false,
+            ..Default::default()
+        };
+        let mut fs = FrequencySensor::new(4, 2);
+        for _ in 0..100 {
+            fs.process_buckets(&mut vec![0., 0.1, 0.5, 1.], &params)
+                .unwrap();
+        }
+        let amp = fs.get_features().get_amplitudes(0);
+        assert_eq!(amp[0], 0.);
+        assert!(amp[1] > 0. && amp[1] < amp[2] && amp[2] < amp[3]);
+        assert_eq!(fs.get_features().get_frame_count(), 100);
+
+        assert!(fs.process_buckets(&mut vec![1.; 3], &params).is_err());
+    }
+
+    #[test]
+    fn summary() {
+        let params = FrequencySensorParams::default();
+        let mut fs = FrequencySensor::new(16, 2);
+        for i in 0..20 {
+            let mut input: Vec<f64> = (0..16).map(|j| ((i * j) % 5) as f64 / 5.).collect();
+            fs.process(&mut input, &params);
+        }
+        let features = fs.get_features();
+        let centers: Vec<f64> = (0..16).map(|i| 100. * (i + 1) as f64).collect();
+        let summary = features.summary(8, &centers);
+
+        assert_eq!(summary.bands.len(), 8);
+        let energy: f64 = features.get_energy().iter().sum();
+        assert!((summary.energy as f64 - energy).abs() <= 1e-6 * energy.abs().max(1.));
+        assert!(summary.centroid >= 100. && summary.centroid <= 1600.);
+        let amplitudes = features.get_amplitudes(0);
+        assert_eq!(
+            summary.bands[0],
+            ((amplitudes[0] + amplitudes[1]) / 2.) as f32
+        );
+    }
+
+    #[test]
+    fn contiguous_history() {
+        let params = FrequencySensorParams::default();
+        let mut fs = FrequencySensor::new(4, 3);
+        for i in 0..5 {
+            fs.process(&mut vec![i as f64; 4], &params);
+        }
+        let features = fs.get_features();
+        let mut out = vec![0f32; 12];
+        features.contiguous_history(&mut out);
+        for (row, i) in out.chunks(4).zip([2, 1, 0].iter()) {
+            let expected: Vec<f32> = features
+                .get_amplitudes(*i)
+                .iter()
+                .map(|&x| x as f32)
+                .collect();
+            assert_eq!(row, &expected[..]);
+        }
+    }
+
+    #[test]
+    fn soft_knee() {
+        let knee = SoftKnee {
+            threshold: 1.,
+            width: 0.5,
+            ratio: 4.,
+        };
+        for &x in &[-1., 0., 0.5, 0.75] {
+            assert_eq!(knee.apply(x), x);
+        }
+        assert!((knee.apply(3.) - 1.5).abs() < 1e-12);
+
+        // the output and its slope are continuous through the knee
+        let slope = |x: f64| (knee.apply(x + 1e-6) - knee.apply(x - 1e-6)) / 2e-6;
+        assert!((slope(0.75) - 1.).abs() < 1e-3);
+        assert!((slope(1.25) - 0.25).abs() < 1e-3);
+        let mut last = (knee.apply(0.7), slope(0.7));
+        for i in 1..=60 {
+            let x = 0.7 + i as f64 * 0.01;
+            let (y, s) = (knee.apply(x), slope(x));
+            assert!(y > last.0 && s <= last.1 + 1e-6 && (s - last.1).abs() < 0.1);
+            assert!(y <= x);
+            last = (y, s);
+        }
+
+        let params = FrequencySensorParams {
+            soft_knee: Some(SoftKnee { ratio: 0.5, ..knee }),
+            ..Default::default()
+        };
+        assert!(params.validate().is_err());
+    }
+
+    #[test]
+    fn sync_weighted() {
+        // returns the energy pushed into the outer buckets by a loud and a quiet bucket
+        let run = |sync_weighted: bool| {
+            let params = FrequencySensorParams {
+                sync: 0.1,
+                sync_weighted,
+                ..Default::default()
+            };
+            let mut fs = FrequencySensor::new(5, 1);
+            fs.features.energy = vec![0., 1., 0., 1., 0.];
+            fs.features.amplitudes[0] = vec![0., 2., 0., 0.1, 0.];
+            fs.apply_sync(&params);
+            (fs.features.energy[0], fs.features.energy[4])
+        };
+
+        let (loud, quiet) = run(false);
+        assert!((loud / quiet - 1.).abs() < 0.5, "{} {}", loud, quiet);
+        let (loud, quiet) = run(true);
+        assert!(loud > 4. * quiet, "{} {}", loud, quiet);
+    }
+
+    #[test]
+    fn reset_filters() {
+        let mut params = FrequencySensorParams {
+            preemphasis: 5.,
+            amp_filter: FilterParams::new(1., 1.),
+            peak_release: FilterParams::new(3., 0.5),
+            sync_enabled: false,
+            ..Default::default()
+        };
+        params.gain_control.kp = 1.;
+        params.reset_filters();
+
+        let d = FrequencySensorParams::default();
+        assert_eq!(params.amp_filter, d.amp_filter);
+        assert_eq!(params.peak_release, d.peak_release);
+        assert_eq!(params.preemphasis, 5.);
+        assert_eq!(params.gain_control.kp, 1.);
+        assert!(!params.sync_enabled);
+
+        params.reset_gain();
+        params.reset_stages();
+        assert_eq!(params.gain_control, d.gain_control);
+        assert!(params.sync_enabled);
+        assert_eq!(params.preemphasis, 5.);
+    }
+
+    #[test]
+    fn load_state() {
+        let params = FrequencySensorParams::default();
+        let input: Vec<f64> = (0..4).map(|i| i as f64 / 4.).collect();
+        let mut fs = FrequencySensor::new(4, 2);
+        for _ in 0..10 {
+            fs.process(&mut input.clone(), &params);
+        }
+        let state = fs.get_state();
+
+        let mut resumed = FrequencySensor::new(4, 2);
+        resumed.load_state(&state).unwrap();
+        assert_eq!(resumed.get_state(), state);
+        fs.process(&mut input.clone(), &params);
+        resumed.process(&mut input.clone(), &params);
+        assert_eq!(resumed.get_state(), fs.get_state());
+
+        let mut other = FrequencySensor::new(8, 2);
+        assert!(other.load_state(&state).is_err());
+        assert_eq!(other.get_state(), FrequencySensor::new(8, 2).get_state());
+    }
+
+    #[test]
+    fn display_filter() {
+        let run = |display_filter: FilterParams| {
+            let params = FrequencySensorParams {
+                amp_filter: FilterParams::new(1., 1.),
+                display_filter,
+                ..Default::default()
+            };
+            let mut fs = FrequencySensor::new(4, 2);
+            let mut variation = 0.;
+            let mut diffs = Vec::new();
+            for i in 0..200 {
+                let x = if i % 3 == 0 { 1. } else { 0.1 };
+                fs.process(&mut vec![x; 4], &params);
+                let f = fs.get_features();
+                if i >= 100 {
+                    variation += (f.get_amplitudes(0)[2] - f.get_amplitudes(1)[2]).abs();
+                }
+                diffs.push(f.get_diff().clone());
+            }
+            (variation, diffs)
+        };
+
+        let (raw, raw_diffs) = run(FilterParams::default());
+        let (smooth, smooth_diffs) = run(FilterParams::new(8., 1.));
+        assert!(smooth < 0.5 * raw, "{} {}", smooth, raw);
+        assert_eq!(smooth_diffs, raw_diffs);
+    }
+
+    #[test]
+    fn scale_gamma() {
+        // returns the ratio of the loudest to the quietest scaled amplitude
+        let run = |scale_gamma: f64| {
+            let params = FrequencySensorParams {
+                scale_mode: ScaleMode::Variance,
+                scale_gamma,
+                ..Default::default()
+            };
+            let mut fs = FrequencySensor::new(4, 2);
+            for i in 0..200 {
+                let x = if i % 2 == 0 { 1. } else { 0.5 };
+                fs.process(&mut vec![x, 2. * x, 3. * x, 4. * x], &params);
+            }
+            let f = fs.get_features();
+            let scaled: Vec<f64> = f
+                .get_scales()
+                .iter()
+                .zip(f.get_amplitudes(0).iter())
+                .map(|(s, a)| s * a)
+                .collect();
+            let max = scaled.iter().cloned().fold(f64::MIN, f64::max);
+            let min = scaled.iter().cloned().fold(f64::MAX, f64::min);
+            max / min
+        };
+
+        let linear = run(1.);
+        assert!(run(0.5) < linear, "{} {}", run(0.5), linear);
+        assert!(run(2.) > linear, "{} {}", run(2.), linear);
+    }
+
+    #[test]
+    fn reset() {
+        let mut fs = FrequencySensor::new(4, 3);
+        fs.set_metadata(vec![1., 2., 3., 4.]).unwrap();
+        for _ in 0..10 {
+            fs.process(&mut vec![0.5; 4], &FrequencySensorParams::default());
+        }
+        fs.reset();
+        let features = fs.get_features();
+        assert_eq!(features.get_frame_count(), 0);
+        assert_eq!(features.get_size(), (4, 3));
+        assert_eq!(features.get_energy(), &vec![0.; 4]);
+        assert_eq!(features.get_metadata(), &vec![1., 2., 3., 4.]);
+        assert_eq!(fs.get_state(), FrequencySensor::new(4, 3).get_state());
+    }
+
+    #[test]
+    fn sync_wrap() {
+        // returns the energy pushed from the first bucket into its neighbors on either side
+        let run = |sync_wrap: bool| {
+            let params = FrequencySensorParams {
+                sync: 0.1,
+                sync_wrap,
+                ..Default::default()
+            };
+            let mut fs = FrequencySensor::new(5, 1);
+            fs.features.energy = vec![1., 0., 0., 0., 0.];
+            fs.apply_sync(&params);
+            (fs.features.energy[1], fs.features.energy[4])
+        };
+
+        let (next, prev) = run(false);
+        assert!(next > 0.);
+        assert!(prev < 0.1 * next, "{} {}", next, prev);
+        let (next, prev) = run(true);
+        assert!((prev / next - 1.).abs() < 0.1, "{} {}", next, prev);
+    }
+
+    #[test]
+    fn sync_radius() {
+        // returns the energy an impulse in the middle bucket spreads to two buckets away
+        let run = |sync_radius: usize| {
+            let params = FrequencySensorParams {
+                sync: 0.1,
+                sync_radius,
+                ..Default::default()
+            };
+            let mut fs = FrequencySensor::new(7, 1);
+            fs.features.energy = vec![0., 0., 0., 1., 0., 0., 0.];
+            fs.apply_sync(&params);
+            fs.features.energy[1]
+        };
+
+        // with radius 1 only the pull towards the mean reaches it
+        assert!(run(2) > 10. * run(1), "{} {}", run(2), run(1));
+    }
+
+    #[test]
+    fn energy_bounds() {
+        // a negative drag adds a constant to the energy every frame
+        let params = FrequencySensorParams {
+            drag: -1e5,
+            sync_enabled: false,
+            ..Default::default()
+        };
+        let mut fs = FrequencySensor::new(4, 2);
+        for _ in 0..100 {
+            fs.process(&mut vec![1.; 4], &params);
+        }
+        assert_eq!(fs.get_features().get_energy(), &vec![1e6; 4]);
+
+        let params = FrequencySensorParams {
+            energy_min: 1.,
+            energy_max: 0.,
+            ..Default::default()
+        };
+        assert!(params.validate().is_err());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn serialize_history() {
+        let params = FrequencySensorParams::default();
+        let mut fs = FrequencySensor::new(4, 3);
+        for i in 0..5 {
+            fs.process(&mut vec![i as f64; 4], &params);
+        }
+        let features = fs.get_features();
+
+        let mut out = Vec::new();
+        features
+            .serialize_history(&mut serde_json::Serializer::new(&mut out))
+            .unwrap();
+        let v: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let rows = v["amplitudes"].as_array().unwrap();
+        assert_eq!(rows.len(), 3);
+        for (row, i) in rows.iter().zip([2, 1, 0].iter()) {
+            assert_eq!(row, &serde_json::json!(features.get_amplitudes(*i)));
+        }
+        assert_eq!(v["frame_count"], 5);
+
+        // the default serialization is of the latest frame only
+        let v = serde_json::to_value(features).unwrap();
+        assert_eq!(v["amplitudes"], rows[2]);
+    }
+
+    #[test]
+    fn iter_amplitudes() {
+        let params = FrequencySensorParams::default();
+        let mut fs = FrequencySensor::new(2, 4);
+        for i in 0..6 {
+            fs.process(&mut vec![i as f64; 2], &params);
+        }
+        let features = fs.get_features();
+        let mut expected: Vec<&Vec<f64>> = (0..4).map(|i| features.get_amplitudes(i)).collect();
+        expected.reverse();
+        assert_eq!(features.iter_amplitudes().collect::<Vec<_>>(), expected);
+
+        let range: Vec<_> = features.get_amplitudes_range(1, 2).collect();
+        assert_eq!(
+            range,
+            vec![features.get_amplitudes(2), features.get_amplitudes(1)]
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn load_state_resumes_output() {
+        let params = FrequencySensorParams {
+            scale_mode: ScaleMode::Variance,
+            display_filter: FilterParams::new(4., 1.),
+            noise_window: 7,
+            ..Default::default()
+        };
+        let input = |k: usize| -> Vec<f64> { (0..4).map(|i| ((k * (i + 3)) % 5) as f64).collect() };
+        let mut fs = FrequencySensor::new(4, 2);
+        // stop at the end of a noise window, where the current minimums are infinite
+        for k in 0..7 {
+            fs.process(&mut input(k), &params);
+        }
+
+        // the state goes through JSON, as it would to be saved across a restart
+        let json = serde_json::to_string(&fs.get_state()).unwrap();
+        let mut resumed = FrequencySensor::new(4, 2);
+        resumed
+            .load_state(&serde_json::from_str(&json).unwrap())
+            .unwrap();
+        for k in 7..30 {
+            fs.process(&mut input(k), &params);
+            resumed.process(&mut input(k), &params);
+            let (f, r) = (fs.get_features(), resumed.get_features());
+            assert_eq!(f.get_amplitudes(0), r.get_amplitudes(0));
+            assert_eq!(f.get_scales(), r.get_scales());
+            assert_eq!(f.get_energy(), r.get_energy());
+            assert_eq!(f.get_noise_floor(), r.get_noise_floor());
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn set_history_length_zero() {
+        FrequencySensor::new(4, 4).set_history_length(0);
+    }
+}
@@ -1,44 +1,155 @@
 use core::fmt::Write;
 
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 
-use crate::filter::{Filter, FilterParams};
-use crate::util::VecFmt;
+use crate::filter::{BiasedFilter, FilterParams};
+use crate::util::{lerp, lerp_usize, pick, VecFmt};
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+/// GainMode selects whether each bucket gets its own gain or whether a single gain, driven by
+/// the mean level across all buckets, is applied to every bucket.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+pub enum GainMode {
+    #[default]
+    PerBucket,
+    Global,
+}
+
+/// Fields missing from serialized params take their value from `Params::default`.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(default)]
 pub struct Params {
+    /// attack_filter smooths the level the controller sees while it is rising.
+    #[serde(alias = "filter_attack")]
+    pub attack_filter: FilterParams,
+    /// release_filter smooths the level the controller sees while it is falling. Set it equal
+    /// to `attack_filter` for symmetric smoothing.
+    #[serde(alias = "filter_release")]
+    pub release_filter: FilterParams,
+    /// filter_params is the single symmetric filter from before the attack and release were
+    /// split. When it is changed from its default it is used for both, in place of
+    /// `attack_filter` and `release_filter`, and it is only serialized then.
+    #[deprecated(note = "set attack_filter and release_filter instead")]
+    #[serde(skip_serializing_if = "is_default_filter")]
     pub filter_params: FilterParams,
     pub kp: f64,
     pub kd: f64,
     pub ki: f64,
+    /// target is the filtered level the controller drives each bucket towards.
+    pub target: f64,
+    /// min_gain and max_gain bound the gain applied to each bucket.
+    pub min_gain: f64,
+    pub max_gain: f64,
     pub pre_gain: f64,
+    pub gain_mode: GainMode,
+    /// clip_threshold is the absolute raw sample level at which `BoostController` reports
+    /// clipping.
+    pub clip_threshold: f64,
+    /// clip_hold is the number of blocks the clip flag stays set after the last clipping block.
+    pub clip_hold: usize,
 }
 
 impl Default for Params {
+    #[allow(deprecated)]
     fn default() -> Self {
         Self {
             kd: 0.1,
             kp: 0.1,
             ki: 0.1,
+            target: 1.0,
+            min_gain: 1e-6,
+            max_gain: 1e6,
             pre_gain: 1.0,
-            filter_params: FilterParams::new(100., 1.),
+            attack_filter: default_filter(),
+            release_filter: default_filter(),
+            filter_params: default_filter(),
+            gain_mode: GainMode::PerBucket,
+            clip_threshold: 0.99,
+            clip_hold: 10,
         }
     }
 }
 
-/// GainController is a PID controller which adjusts gain with a target value of 1.
+impl Params {
+    /// lerp interpolates between two sets of params, see `AnalyzerParams::lerp`.
+    #[allow(deprecated)]
+    pub fn lerp(a: &Self, b: &Self, t: f64) -> Self {
+        Self {
+            attack_filter: FilterParams::lerp(&a.attack_filter, &b.attack_filter, t),
+            release_filter: FilterParams::lerp(&a.release_filter, &b.release_filter, t),
+            filter_params: FilterParams::lerp(&a.filter_params, &b.filter_params, t),
+            kp: lerp(a.kp, b.kp, t),
+            kd: lerp(a.kd, b.kd, t),
+            ki: lerp(a.ki, b.ki, t),
+            target: lerp(a.target, b.target, t),
+            min_gain: lerp(a.min_gain, b.min_gain, t),
+            max_gain: lerp(a.max_gain, b.max_gain, t),
+            pre_gain: lerp(a.pre_gain, b.pre_gain, t),
+            gain_mode: pick(a.gain_mode, b.gain_mode, t),
+            clip_threshold: lerp(a.clip_threshold, b.clip_threshold, t),
+            clip_hold: lerp_usize(a.clip_hold, b.clip_hold, t),
+        }
+    }
+
+    /// level_filters returns the filters that smooth the level while it is rising and falling,
+    /// which are both the deprecated `filter_params` when it is changed from its default.
+    #[allow(deprecated)]
+    pub fn level_filters(&self) -> (&FilterParams, &FilterParams) {
+        if is_default_filter(&self.filter_params) {
+            (&self.attack_filter, &self.release_filter)
+        } else {
+            (&self.filter_params, &self.filter_params)
+        }
+    }
+
+    /// validate returns an error if the target is not a positive, finite level, for which the
+    /// controller's error is undefined.
+    pub fn validate(&self) -> Result<()> {
+        if !(self.target > 0. && self.target.is_finite()) {
+            return Err(anyhow!(
+                "gain control target must be positive and finite, got {}",
+                self.target
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_filter() -> FilterParams {
+    FilterParams::new(100., 1.)
+}
+
+fn is_default_filter(f: &FilterParams) -> bool {
+    *f == default_filter()
+}
+
+/// GainController is a PID controller which adjusts gain towards the target value set in
+/// `Params`, 1 by default. In `GainMode::Global` every bucket is driven by the error of the mean
+/// level, so all gains stay equal and the relative shape of the input is preserved. The level is
+/// smoothed with `Params::attack_filter` while it rises and `Params::release_filter` while it
+/// falls, so that gain can be pulled down quickly on transients and recover slowly afterwards
+/// without pumping.
+///
+/// Each frame, with e the error of the filtered level and e' the error of the previous frame,
+/// the integral accumulates as `err = 0.99 * err + 0.01 * e` and the gain is updated by
+/// `kp * e + ki * err + kd * (e - e')`. When that update would take the gain outside of its
+/// bounds the gain is clamped and the integral is left as it was, so it doesn't wind up while
+/// the gain is saturated.
 pub struct GainController {
-    filter: Filter,
+    filter: BiasedFilter,
     values: Vec<f64>,
     err: Vec<f64>,
+    // the error of the previous frame, for the derivative term
+    last_error: Vec<f64>,
 }
 
 impl GainController {
     pub fn new(size: usize) -> GainController {
         GainController {
-            filter: Filter::new(size),
+            filter: BiasedFilter::new(size),
             values: vec![1f64; size],
             err: vec![0f64; size],
+            last_error: vec![0f64; size],
         }
     }
 
@@ -50,8 +161,9 @@ impl GainController {
     }
     */
 
-    fn error(x: f64) -> f64 {
-        let x = x.max(0.0000001);
+    // error is the error of a level x relative to the target
+    fn error(x: f64, target: f64) -> f64 {
+        let x = (x / target).max(0.0000001);
         (if x < 1. { 1. / x - 1. } else { 1. - x }).clamp(-32., 32.)
     }
 
@@ -60,19 +172,33 @@ impl GainController {
             input[i] *= self.values[i] * params.pre_gain;
         }
 
-        self.filter.process(input, &params.filter_params);
+        let (attack, release) = params.level_filters();
+        self.filter.process(input, (release, attack));
         let filter_values = self.filter.get_values();
+        let global_error = match params.gain_mode {
+            GainMode::Global => {
+                let mean = filter_values.iter().sum::<f64>() / filter_values.len() as f64;
+                Some(GainController::error(mean, params.target))
+            }
+            GainMode::PerBucket => None,
+        };
 
         for i in 0..input.len() {
-            let e = GainController::error(filter_values[i]);
+            let e = global_error
+                .unwrap_or_else(|| GainController::error(filter_values[i], params.target));
             // "integrate" error
-            self.err[i] = 0.99 * self.err[i] + 0.01 * e;
+            let err = 0.99 * self.err[i] + 0.01 * e;
+            let de = e - self.last_error[i];
+            self.last_error[i] = e;
 
-            let u = params.kp * e + params.ki * self.err[i] + params.kd * (self.err[i] - e);
+            let u = params.kp * e + params.ki * err + params.kd * de;
             self.values[i] = match self.values[i] + u {
-                x if x > 1e6 => 1e6,
-                x if x < 1e-6 => 1e-6,
-                x => x,
+                x if x > params.max_gain => params.max_gain,
+                x if x < params.min_gain => params.min_gain,
+                x => {
+                    self.err[i] = err;
+                    x
+                }
             };
         }
     }
@@ -81,20 +207,59 @@ impl GainController {
         &self.values
     }
 
+    /// reset restores the gains to 1 and clears the error and level filter, e.g. when
+    /// switching inputs so the old gains don't cause a pop.
+    pub fn reset(&mut self) {
+        for x in self.values.iter_mut() {
+            *x = 1.;
+        }
+        for x in self.err.iter_mut().chain(self.last_error.iter_mut()) {
+            *x = 0.;
+        }
+        self.filter.reset();
+    }
+
     pub fn get_state(&self) -> State {
         State {
             gain: self.values.to_owned(),
             filter_values: self.filter.get_values().to_owned(),
             err: self.err.to_owned(),
+            last_error: self.last_error.to_owned(),
+        }
+    }
+
+    /// load_state restores the state captured by `get_state`. Nothing is changed if the state
+    /// is for a different number of buckets.
+    pub fn load_state(&mut self, state: &State) -> Result<()> {
+        let size = self.values.len();
+        for v in [
+            &state.gain,
+            &state.filter_values,
+            &state.err,
+            &state.last_error,
+        ] {
+            if v.len() != size {
+                return Err(anyhow!(
+                    "gain controller state has {} values, expected {}",
+                    v.len(),
+                    size
+                ));
+            }
         }
+        self.values.copy_from_slice(&state.gain);
+        self.filter.set_values(&state.filter_values)?;
+        self.err.copy_from_slice(&state.err);
+        self.last_error.copy_from_slice(&state.last_error);
+        Ok(())
     }
 }
 
-#[derive(Serialize, Debug, Default, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct State {
     pub gain: Vec<f64>,
     pub filter_values: Vec<f64>,
     pub err: Vec<f64>,
+    pub last_error: Vec<f64>,
 }
 
 impl State {
@@ -108,43 +273,101 @@ impl State {
     }
 }
 
+/// BoostController applies a single gain to a whole frame based on its RMS level. The applied
+/// gain follows the controller through an attack/release filter so that it can ramp
+/// asymmetrically on transients. It also detects clipping of the raw input.
 pub struct BoostController {
     gc: GainController,
+    // the rms level of the current frame, as the input of the gain controller
+    level: Vec<f64>,
+    scale: BiasedFilter,
+    clip_count: usize,
 }
 
 impl BoostController {
     pub fn new() -> Self {
+        let mut scale = BiasedFilter::new(1);
+        scale.get_values_mut()[0] = 1.;
         Self {
             gc: GainController::new(1),
+            level: vec![0.],
+            scale,
+            clip_count: 0,
         }
     }
 
-    pub fn process(&mut self, frame: &mut Vec<f64>, params: &Params) {
+    /// process scales the frame by the boost gain. The gain follows the controller through
+    /// `attack` while it is rising and `release` while it is falling.
+    pub fn process(
+        &mut self,
+        frame: &mut [f64],
+        params: &Params,
+        attack: &FilterParams,
+        release: &FilterParams,
+    ) {
+        self.detect_clip(frame, params);
+
         let s: f64 = frame.iter().map(|x: &f64| x * x).sum();
-        let rms = (s / frame.len() as f64).sqrt();
-        let mut p = vec![rms];
-        self.gc.process(&mut p, params);
-        let scale = self.gc.get_values()[0];
+        self.level[0] = (s / frame.len() as f64).sqrt();
+        self.gc.process(&mut self.level, params);
+        self.scale.process(self.gc.get_values(), (release, attack));
+        let scale = self.scale.get_values()[0];
         for i in 0..frame.len() {
             frame[i] *= scale;
         }
     }
 
+    fn detect_clip(&mut self, frame: &[f64], params: &Params) {
+        if frame.iter().any(|x| x.abs() >= params.clip_threshold) {
+            self.clip_count = params.clip_hold + 1;
+        } else {
+            self.clip_count = self.clip_count.saturating_sub(1);
+        }
+    }
+
+    /// reset restores the gain to 1 and clears the controller and clip state.
+    pub fn reset(&mut self) {
+        self.gc.reset();
+        self.scale.reset_to(1.);
+        self.level[0] = 0.;
+        self.clip_count = 0;
+    }
+
     pub fn get_state(&self) -> BoostState {
         let s = self.gc.get_state();
         BoostState {
             gain: s.gain[0],
+            applied_gain: self.scale.get_values()[0],
             filter_value: s.filter_values[0],
             err: s.err[0],
+            last_error: s.last_error[0],
+            clipping: self.clip_count > 0,
         }
     }
+
+    /// load_state restores the state captured by `get_state`, except for the clip flag, which
+    /// is cleared.
+    pub fn load_state(&mut self, state: &BoostState) -> Result<()> {
+        self.gc.load_state(&State {
+            gain: vec![state.gain],
+            filter_values: vec![state.filter_value],
+            err: vec![state.err],
+            last_error: vec![state.last_error],
+        })?;
+        self.scale.set_values(&[state.applied_gain])?;
+        self.clip_count = 0;
+        Ok(())
+    }
 }
 
-#[derive(Debug, Serialize, Default, Clone)]
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq)]
 pub struct BoostState {
     pub gain: f64,
+    pub applied_gain: f64,
     pub filter_value: f64,
     pub err: f64,
+    pub last_error: f64,
+    pub clipping: bool,
 }
 
 impl BoostState {
@@ -153,7 +376,291 @@ impl BoostState {
         W: Write,
     {
         writeln!(w, "\t\"boost\":          {},", self.gain)?;
+        writeln!(w, "\t\"boost_applied\":  {},", self.applied_gain)?;
         writeln!(w, "\t\"boost_filter\":   {},", self.filter_value)?;
-        writeln!(w, "\t\"boost_err\":      {},", self.err)
+        writeln!(w, "\t\"boost_err\":      {},", self.err)?;
+        writeln!(w, "\t\"boost_clipping\": {},", self.clipping)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BoostController, GainController, GainMode, Params};
+    use crate::filter::FilterParams;
+
+    // the default gains are tuned for noisy spectra and ring on a constant input
+    fn stable_params(gain_mode: GainMode) -> Params {
+        Params {
+            kp: 0.005,
+            ki: 0.005,
+            kd: 0.,
+            attack_filter: FilterParams::new(4., 1.),
+            release_filter: FilterParams::new(4., 1.),
+            gain_mode,
+            ..Default::default()
+        }
+    }
+
+    fn settle(gc: &mut GainController, input: &[f64], params: &Params) -> Vec<f64> {
+        for _ in 0..4000 {
+            gc.process(&mut input.to_vec(), params);
+        }
+        let mut out = input.to_vec();
+        gc.process(&mut out, params);
+        out
+    }
+
+    #[test]
+    fn global_mode_preserves_shape() {
+        let params = stable_params(GainMode::Global);
+        let mut gc = GainController::new(2);
+        for &level in &[0.1, 10.] {
+            let out = settle(&mut gc, &[level, 4. * level], &params);
+            assert!((out[1] / out[0] - 4.).abs() < 1e-9, "{:?}", out);
+        }
+    }
+
+    #[test]
+    fn per_bucket_mode_equalizes() {
+        let params = stable_params(GainMode::PerBucket);
+        let mut gc = GainController::new(2);
+        for &level in &[0.1, 10.] {
+            let out = settle(&mut gc, &[level, 4. * level], &params);
+            assert!((out[1] / out[0] - 1.).abs() < 0.05, "{:?}", out);
+        }
+    }
+
+    #[test]
+    fn boost_release_is_slow() {
+        let params = stable_params(GainMode::PerBucket);
+        let fast = FilterParams::default();
+        let slow = FilterParams::new(50., 1.);
+
+        let mut a = BoostController::new();
+        let mut b = BoostController::new();
+        for _ in 0..1000 {
+            a.process(&mut [0.1; 8], &params, &fast, &fast);
+            b.process(&mut [0.1; 8], &params, &fast, &slow);
+        }
+        let before = b.get_state().applied_gain;
+        assert!((a.get_state().applied_gain - before).abs() < 1e-6);
+
+        for _ in 0..10 {
+            a.process(&mut [1.; 8], &params, &fast, &fast);
+            b.process(&mut [1.; 8], &params, &fast, &slow);
+        }
+        let fast_state = a.get_state();
+        let slow_state = b.get_state();
+        // without smoothing the applied gain tracks the controller
+        assert_eq!(fast_state.applied_gain, fast_state.gain);
+        assert_eq!(slow_state.gain, fast_state.gain);
+        // with a slow release it lags well behind the controller
+        let fast_drop = before - fast_state.applied_gain;
+        let slow_drop = before - slow_state.applied_gain;
+        assert!(slow_drop > 0.);
+        assert!(slow_drop < 0.1 * fast_drop, "{} {}", slow_drop, fast_drop);
+    }
+
+    #[test]
+    fn clip_hold() {
+        let params = Params {
+            clip_hold: 3,
+            ..Default::default()
+        };
+        let f = FilterParams::default();
+        let mut b = BoostController::new();
+        b.process(&mut [0.5; 8], &params, &f, &f);
+        assert!(!b.get_state().clipping);
+
+        let mut clipped = vec![0.5; 8];
+        clipped[3] = -1.;
+        b.process(&mut clipped, &params, &f, &f);
+        assert!(b.get_state().clipping);
+
+        for _ in 0..3 {
+            b.process(&mut [0.5; 8], &params, &f, &f);
+            assert!(b.get_state().clipping);
+        }
+        b.process(&mut [0.5; 8], &params, &f, &f);
+        assert!(!b.get_state().clipping);
+    }
+
+    #[test]
+    fn attack_release() {
+        let symmetric = stable_params(GainMode::PerBucket);
+        let biased = Params {
+            release_filter: FilterParams::new(100., 1.),
+            ..symmetric
+        };
+
+        // returns how far the gain moves in the blocks after stepping between two levels
+        let step = |params: &Params, from: f64, to: f64| {
+            let mut gc = GainController::new(1);
+            settle(&mut gc, &[from], params);
+            let before = gc.get_values()[0];
+            for _ in 0..20 {
+                gc.process(&mut vec![to], params);
+            }
+            (gc.get_values()[0] - before).abs()
+        };
+
+        // a rising level is tracked with the same attack
+        let sym_down = step(&symmetric, 1., 4.);
+        let biased_down = step(&biased, 1., 4.);
+        assert!(
+            (biased_down / sym_down - 1.).abs() < 0.1,
+            "{} {}",
+            biased_down,
+            sym_down
+        );
+
+        // a falling level is tracked slowly with the release
+        let sym_up = step(&symmetric, 4., 1.);
+        let biased_up = step(&biased, 4., 1.);
+        assert!(biased_up < 0.5 * sym_up, "{} {}", biased_up, sym_up);
+        assert!(
+            biased_up < 0.5 * biased_down,
+            "{} {}",
+            biased_up,
+            biased_down
+        );
+    }
+
+    #[test]
+    fn target() {
+        let params = Params {
+            target: 2.,
+            ..stable_params(GainMode::PerBucket)
+        };
+        let mut gc = GainController::new(2);
+        for &level in &[0.5, 4.] {
+            let out = settle(&mut gc, &[level, 2. * level], &params);
+            for x in out {
+                assert!((x - 2.).abs() < 0.05, "{} {}", x, level);
+            }
+        }
+    }
+
+    #[test]
+    fn anti_windup() {
+        // silence drives the gain to its upper bound in one frame
+        let params = Params {
+            kp: 1e6,
+            ..stable_params(GainMode::PerBucket)
+        };
+        let mut gc = GainController::new(1);
+        for _ in 0..100 {
+            gc.process(&mut vec![0.], &params);
+            assert_eq!(gc.get_values()[0], 1e6);
+        }
+        // the integral didn't grow while the gain was saturated
+        assert_eq!(gc.get_state().err, vec![0.]);
+
+        // it integrates again once the gain is within bounds
+        for _ in 0..10 {
+            gc.process(&mut vec![1e-5], &stable_params(GainMode::PerBucket));
+        }
+        assert!(gc.get_state().err[0] != 0.);
+    }
+
+    #[test]
+    fn max_gain() {
+        let params = Params {
+            max_gain: 10.,
+            ..Default::default()
+        };
+        let mut gc = GainController::new(2);
+        for _ in 0..1000 {
+            gc.process(&mut vec![1e-4, 0.], &params);
+            assert!(gc.get_values().iter().all(|&g| g <= 10.));
+        }
+        assert_eq!(gc.get_values(), &vec![10.; 2]);
+    }
+
+    #[test]
+    fn reset_and_load_state() {
+        let params = stable_params(GainMode::PerBucket);
+        let mut gc = GainController::new(2);
+        settle(&mut gc, &[0.2, 3.], &params);
+        let state = gc.get_state();
+
+        let mut restored = GainController::new(2);
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.get_state(), state);
+        assert!(GainController::new(3).load_state(&state).is_err());
+
+        gc.reset();
+        assert_eq!(gc.get_state(), GainController::new(2).get_state());
+
+        let f = FilterParams::default();
+        let mut b = BoostController::new();
+        for _ in 0..100 {
+            b.process(&mut [0.1; 8], &params, &f, &f);
+        }
+        let state = b.get_state();
+        let mut restored = BoostController::new();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.get_state(), state);
+        let (mut x, mut y) = ([0.3; 8], [0.3; 8]);
+        b.process(&mut x, &params, &f, &f);
+        restored.process(&mut y, &params, &f, &f);
+        assert_eq!(x, y);
+
+        b.reset();
+        assert_eq!(b.get_state(), BoostController::new().get_state());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    #[allow(deprecated)]
+    fn attack_release_aliases() {
+        use super::is_default_filter;
+
+        let params: Params = serde_json::from_str(
+            r#"{"filter_attack": {"tau": 2, "gain": 1}, "release_filter": {"tau": 50, "gain": 1}}"#,
+        )
+        .unwrap();
+        assert_eq!(params.attack_filter, FilterParams::new(2., 1.));
+        assert_eq!(params.release_filter, FilterParams::new(50., 1.));
+        assert!(is_default_filter(&params.filter_params));
+        assert!(!serde_json::to_string(&params)
+            .unwrap()
+            .contains("filter_params"));
+
+        // the deprecated filter_params is used for both directions
+        let params: Params =
+            serde_json::from_str(r#"{"filter_params": {"tau": 8, "gain": 1}}"#).unwrap();
+        let f = FilterParams::new(8., 1.);
+        assert_eq!(params.level_filters(), (&f, &f));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn deprecated_filter_params() {
+        let params = Params::default();
+        assert_eq!(
+            params.level_filters(),
+            (&params.attack_filter, &params.release_filter)
+        );
+
+        let f = FilterParams::new(8., 1.);
+        let params = Params {
+            filter_params: f,
+            attack_filter: FilterParams::new(2., 1.),
+            ..Default::default()
+        };
+        assert_eq!(params.level_filters(), (&f, &f));
+    }
+
+    #[test]
+    fn validate_target() {
+        assert!(Params::default().validate().is_ok());
+        for &target in [0., -1., f64::INFINITY, f64::NAN].iter() {
+            let params = Params {
+                target,
+                ..Default::default()
+            };
+            assert!(params.validate().is_err(), "{}", target);
+        }
     }
 }
@@ -0,0 +1,117 @@
+use crate::frequency_sensor::Features;
+
+/// Histogrammer counts the distribution of amplitude values over the recent frames held in
+/// `Features`, which can be used to pick a display contrast. Values outside of [min, max] are
+/// counted in the first or last bin. The histogram is global, over the values of every bucket;
+/// for a histogram of a single bucket, `add` its values to a histogrammer of its own.
+pub struct Histogrammer {
+    counts: Vec<usize>,
+    total: usize,
+    min: f64,
+    max: f64,
+}
+
+impl Histogrammer {
+    pub fn new(bins: usize, min: f64, max: f64) -> Histogrammer {
+        if bins == 0 || max <= min {
+            panic!("histogram needs at least one bin and max > min");
+        }
+        Histogrammer {
+            counts: vec![0; bins],
+            total: 0,
+            min,
+            max,
+        }
+    }
+
+    fn bin_width(&self) -> f64 {
+        (self.max - self.min) / self.counts.len() as f64
+    }
+
+    pub fn clear(&mut self) {
+        for c in self.counts.iter_mut() {
+            *c = 0;
+        }
+        self.total = 0;
+    }
+
+    /// add counts each of the values
+    pub fn add(&mut self, values: &[f64]) {
+        let last = self.counts.len() - 1;
+        let width = self.bin_width();
+        for x in values {
+            let bin = ((x - self.min) / width).max(0.) as usize;
+            self.counts[bin.min(last)] += 1;
+        }
+        self.total += values.len();
+    }
+
+    /// process replaces the histogram with the distribution of every bucket's amplitude over
+    /// all of the frames of history in `features`.
+    pub fn process(&mut self, features: &Features) {
+        self.clear();
+        let (_, length) = features.get_size();
+        for i in 0..length {
+            self.add(features.get_amplitudes(i));
+        }
+    }
+
+    pub fn get_counts(&self) -> &Vec<usize> {
+        &self.counts
+    }
+
+    /// percentile returns the value below which `p` percent of the counted values lie,
+    /// interpolating linearly within a bin, or `None` if no values have been counted.
+    pub fn percentile(&self, p: f64) -> Option<f64> {
+        if self.total == 0 {
+            return None;
+        }
+        let target = p.clamp(0., 100.) / 100. * self.total as f64;
+        let width = self.bin_width();
+        let mut below = 0.;
+        for (i, &c) in self.counts.iter().enumerate() {
+            let c = c as f64;
+            if c > 0. && below + c >= target {
+                return Some(self.min + width * (i as f64 + (target - below) / c));
+            }
+            below += c;
+        }
+        Some(self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Histogrammer;
+    use crate::frequency_sensor::{FrequencySensor, FrequencySensorParams};
+
+    #[test]
+    fn percentile() {
+        let mut h = Histogrammer::new(100, 0., 1.);
+        let values: Vec<f64> = (0..100).map(|x| (x as f64 + 0.5) / 100.).collect();
+        h.add(&values);
+        assert!((h.percentile(50.).unwrap() - 0.5).abs() < 1e-9);
+        assert!((h.percentile(90.).unwrap() - 0.9).abs() < 1e-9);
+
+        // skewed toward low values
+        h.clear();
+        h.add(&[0.1; 30]);
+        h.add(&[0.9; 10]);
+        assert!(h.percentile(50.).unwrap() < 0.2);
+        assert!(h.percentile(90.).unwrap() > 0.8);
+
+        h.clear();
+        assert_eq!(h.percentile(50.), None);
+    }
+
+    #[test]
+    fn process() {
+        let mut fs = FrequencySensor::new(4, 3);
+        for _ in 0..5 {
+            fs.process(&mut vec![1.; 4], &FrequencySensorParams::default());
+        }
+        let mut h = Histogrammer::new(10, 0., 10.);
+        h.process(fs.get_features());
+        assert_eq!(h.get_counts().iter().sum::<usize>(), 12);
+    }
+}
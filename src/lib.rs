@@ -1,6 +1,8 @@
 extern crate anyhow;
 extern crate cpal;
 extern crate serde;
+#[cfg(feature = "json")]
+extern crate serde_json;
 
 pub mod analyzer;
 pub mod bucketer;
@@ -8,11 +10,20 @@ pub mod errors;
 pub mod filter;
 pub mod frequency_sensor;
 pub mod gain_control;
+pub mod histogram;
+pub mod multi_analyzer;
+#[cfg(feature = "net")]
+pub mod net;
 pub mod sfft;
+#[doc(hidden)]
+pub mod testutil;
+pub mod types;
 
 mod buffer;
 mod source;
 mod util;
 
 pub use analyzer::Analyzer;
-pub use source::{Source, Stream};
+pub use multi_analyzer::MultiAnalyzer;
+pub use source::{i16_block_stats, Source, Stream};
+pub use types::{Buckets, Frame, Spectrum};
@@ -0,0 +1,314 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::analyzer::{Analyzer, AnalyzerParams};
+use crate::frequency_sensor::Features;
+use crate::types::Frame;
+
+/// ChannelParams are the params for a `MultiAnalyzer`, either shared by every channel or given
+/// separately for each one.
+#[derive(Debug, Clone)]
+pub enum ChannelParams {
+    Shared(Box<AnalyzerParams>),
+    PerChannel(Vec<AnalyzerParams>),
+}
+
+impl From<AnalyzerParams> for ChannelParams {
+    fn from(params: AnalyzerParams) -> Self {
+        ChannelParams::Shared(Box::new(params))
+    }
+}
+
+impl From<Vec<AnalyzerParams>> for ChannelParams {
+    fn from(params: Vec<AnalyzerParams>) -> Self {
+        ChannelParams::PerChannel(params)
+    }
+}
+
+/// StereoMode determines which signals are analyzed for a two channel input. In `MidSide` mode
+/// the first channel's features are of the mid signal, (L + R) / 2, and the second channel's
+/// are of the side signal, (L - R) / 2.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Default, PartialEq)]
+pub enum StereoMode {
+    #[default]
+    LeftRight,
+    MidSide,
+}
+
+/// MultiAnalyzer runs an independent `Analyzer` on each channel of a multichannel input.
+pub struct MultiAnalyzer {
+    analyzers: Vec<Analyzer>,
+    params: Vec<AnalyzerParams>,
+    frames: Vec<Frame>,
+    stereo_mode: StereoMode,
+}
+
+impl MultiAnalyzer {
+    /// new creates an analyzer for each of `channels` channels. Per-channel params must contain
+    /// exactly one entry for each channel.
+    pub fn new<P: Into<ChannelParams>>(
+        channels: usize,
+        fft_size: usize,
+        block_size: usize,
+        size: usize,
+        length: usize,
+        params: P,
+    ) -> Result<Self> {
+        let params = match params.into() {
+            ChannelParams::Shared(p) => vec![*p; channels],
+            ChannelParams::PerChannel(p) => {
+                if p.len() != channels {
+                    return Err(anyhow!(
+                        "got params for {} channels, expected {}",
+                        p.len(),
+                        channels
+                    ));
+                }
+                p
+            }
+        };
+        Ok(Self {
+            analyzers: (0..channels)
+                .map(|_| Analyzer::new(fft_size, block_size, size, length))
+                .collect(),
+            params,
+            frames: vec![Frame::default(); channels],
+            stereo_mode: StereoMode::default(),
+        })
+    }
+
+    pub fn get_channels(&self) -> usize {
+        self.analyzers.len()
+    }
+
+    pub fn get_params(&self, channel: usize) -> &AnalyzerParams {
+        &self.params[channel]
+    }
+
+    pub fn set_params(&mut self, channel: usize, params: AnalyzerParams) {
+        self.params[channel] = params;
+    }
+
+    pub fn get_stereo_mode(&self) -> StereoMode {
+        self.stereo_mode
+    }
+
+    /// set_stereo_mode sets how a stereo input is analyzed. Only `LeftRight` is valid for inputs
+    /// which do not have exactly two channels.
+    pub fn set_stereo_mode(&mut self, mode: StereoMode) -> Result<()> {
+        if mode == StereoMode::MidSide && self.analyzers.len() != 2 {
+            return Err(anyhow!(
+                "mid/side mode requires 2 channels, got {}",
+                self.analyzers.len()
+            ));
+        }
+        self.stereo_mode = mode;
+        Ok(())
+    }
+
+    /// process feeds one frame per channel through that channel's analyzer, returning the
+    /// features of each channel that emitted. With per-channel params the channels can emit on
+    /// different blocks, e.g. with different `min_emit_interval_samples`, so each channel's
+    /// features are `None` on the frames where that channel did not emit.
+    pub fn process(&mut self, frames: &mut [Frame]) -> Vec<Option<Features>> {
+        if frames.len() != self.analyzers.len() {
+            panic!(
+                "got {} frames, expected one for each of {} channels",
+                frames.len(),
+                self.analyzers.len()
+            );
+        }
+        if self.stereo_mode == StereoMode::MidSide {
+            let (left, right) = frames.split_at_mut(1);
+            for (l, r) in left[0].iter_mut().zip(right[0].iter_mut()) {
+                let (mid, side) = ((*l + *r) / 2., (*l - *r) / 2.);
+                *l = mid;
+                *r = side;
+            }
+        }
+        self.analyzers
+            .iter_mut()
+            .zip(self.params.iter())
+            .zip(frames.iter_mut())
+            .map(|((a, p), f)| a.process(f, p))
+            .collect()
+    }
+
+    /// process_interleaved splits interleaved samples, as delivered by an input stream, into a
+    /// frame per channel and processes them.
+    pub fn process_interleaved(&mut self, data: &[f64]) -> Vec<Option<Features>> {
+        let channels = self.analyzers.len();
+        let samples = data.chunks_exact(channels);
+        if !samples.remainder().is_empty() {
+            panic!(
+                "interleaved data length {} is not a multiple of {} channels",
+                data.len(),
+                channels
+            );
+        }
+        let mut frames = std::mem::take(&mut self.frames);
+        for frame in frames.iter_mut() {
+            frame.clear();
+        }
+        for sample in samples {
+            for (frame, x) in frames.iter_mut().zip(sample) {
+                frame.push(*x);
+            }
+        }
+        let features = self.process(&mut frames);
+        self.frames = frames;
+        features
+    }
+
+    /// process_planar processes planar input, as delivered by some capture backends, which has a
+    /// separate slice of samples for each channel. Every slice must have the same length.
+    pub fn process_planar(&mut self, channels: &[&[f32]]) -> Vec<Option<Features>> {
+        if channels.len() != self.analyzers.len() {
+            panic!(
+                "got {} planar channels, expected {}",
+                channels.len(),
+                self.analyzers.len()
+            );
+        }
+        let len = channels.first().map_or(0, |c| c.len());
+        if channels.iter().any(|c| c.len() != len) {
+            panic!("planar channels must all have the same length");
+        }
+        let mut frames = std::mem::take(&mut self.frames);
+        for (frame, channel) in frames.iter_mut().zip(channels) {
+            frame.clear();
+            frame.extend(channel.iter().map(|&x| x as f64));
+        }
+        let features = self.process(&mut frames);
+        self.frames = frames;
+        features
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MultiAnalyzer, StereoMode};
+    use crate::analyzer::{Analyzer, AnalyzerParams};
+    use crate::filter::FilterParams;
+    use crate::types::Frame;
+
+    #[test]
+    fn per_channel_params() {
+        let mut smooth = AnalyzerParams::default();
+        smooth.fs.amp_filter = FilterParams::new(64., 1.);
+        let mut fast = AnalyzerParams::default();
+        fast.fs.amp_filter = FilterParams::new(0., 1.);
+
+        let mut m = MultiAnalyzer::new(2, 128, 128, 16, 2, vec![fast, smooth]).unwrap();
+        assert_eq!(m.get_channels(), 2);
+
+        use std::f64::consts::PI;
+        let tone: Frame = (0..128)
+            .map(|x| (x as f64 * 16. * PI / 128.).sin())
+            .collect();
+        let mut features = Vec::new();
+        for _ in 0..4 {
+            features = m.process(&mut [tone.clone(), tone.clone()]);
+        }
+        let total = |i: usize| {
+            let f = features[i].as_ref().unwrap();
+            f.get_amplitudes(0).iter().sum::<f64>()
+        };
+        assert!(total(1) < 0.5 * total(0), "{} {}", total(0), total(1));
+    }
+
+    #[test]
+    fn params_length_must_match() {
+        assert!(MultiAnalyzer::new(2, 128, 128, 16, 2, vec![AnalyzerParams::default()]).is_err());
+        assert!(MultiAnalyzer::new(2, 128, 128, 16, 2, AnalyzerParams::default()).is_ok());
+    }
+
+    #[test]
+    fn process_interleaved() {
+        let mut m = MultiAnalyzer::new(2, 16, 16, 4, 2, AnalyzerParams::default()).unwrap();
+        let data: Vec<f64> = (0..32).map(|x| if x % 2 == 0 { 1. } else { 0. }).collect();
+        let features = m.process_interleaved(&data);
+        let total = |i: usize| {
+            let f = features[i].as_ref().unwrap();
+            f.get_amplitudes(0).iter().sum::<f64>()
+        };
+        assert!(total(0) != total(1));
+    }
+
+    #[test]
+    fn mid_side() {
+        let mut m = MultiAnalyzer::new(2, 128, 128, 16, 2, AnalyzerParams::default()).unwrap();
+        m.set_stereo_mode(StereoMode::MidSide).unwrap();
+
+        use std::f64::consts::PI;
+        let tone: Frame = (0..128)
+            .map(|x| (x as f64 * 16. * PI / 128.).sin())
+            .collect();
+        let mut plain = Analyzer::new(128, 128, 16, 2);
+        let mut features = Vec::new();
+        let mut expected = None;
+        for _ in 0..4 {
+            features = m.process(&mut [tone.clone(), tone.clone()]);
+            expected = plain.process(&mut tone.clone(), &Default::default());
+        }
+        let (mid, side) = (features[0].as_ref().unwrap(), features[1].as_ref().unwrap());
+        assert_eq!(mid.get_amplitudes(0), expected.unwrap().get_amplitudes(0));
+        assert!(side.get_amplitudes(0).iter().all(|&x| x == 0.));
+
+        let mut mono = MultiAnalyzer::new(1, 128, 128, 16, 2, AnalyzerParams::default()).unwrap();
+        assert!(mono.set_stereo_mode(StereoMode::MidSide).is_err());
+    }
+
+    #[test]
+    fn process_planar() {
+        let left: Vec<f32> = (0..64).map(|x| ((x % 7) as f32 - 3.) / 4.).collect();
+        let right: Vec<f32> = (0..64).map(|x| ((x % 3) as f32 - 1.) / 2.).collect();
+        let interleaved: Vec<f64> = left
+            .iter()
+            .zip(right.iter())
+            .flat_map(|(&l, &r)| vec![l as f64, r as f64])
+            .collect();
+
+        let mut planar = MultiAnalyzer::new(2, 32, 32, 4, 2, AnalyzerParams::default()).unwrap();
+        let mut expected = MultiAnalyzer::new(2, 32, 32, 4, 2, AnalyzerParams::default()).unwrap();
+        for _ in 0..4 {
+            let p = planar.process_planar(&[&left[..32], &right[..32]]);
+            let e = expected.process_interleaved(&interleaved[..64]);
+            for (p, e) in p.iter().zip(e.iter()) {
+                let (p, e) = (p.as_ref().unwrap(), e.as_ref().unwrap());
+                assert_eq!(p.get_amplitudes(0), e.get_amplitudes(0));
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn process_planar_lengths_must_match() {
+        let mut m = MultiAnalyzer::new(2, 32, 32, 4, 2, AnalyzerParams::default()).unwrap();
+        m.process_planar(&[&[0.; 32], &[0.; 16]]);
+    }
+
+    #[test]
+    fn channels_emit_independently() {
+        let every_block = AnalyzerParams::default();
+        let every_other = AnalyzerParams {
+            min_emit_interval_samples: 256,
+            ..Default::default()
+        };
+        let mut m = MultiAnalyzer::new(2, 128, 128, 16, 2, vec![every_block, every_other]).unwrap();
+
+        use std::f64::consts::PI;
+        let tone: Frame = (0..128)
+            .map(|x| (x as f64 * 16. * PI / 128.).sin())
+            .collect();
+        let emitted: Vec<(bool, bool)> = (0..6)
+            .map(|_| {
+                let f = m.process(&mut [tone.clone(), tone.clone()]);
+                (f[0].is_some(), f[1].is_some())
+            })
+            .collect();
+        assert!(emitted.iter().all(|e| e.0));
+        assert!(emitted.iter().any(|e| !e.1));
+        assert!(emitted.iter().any(|e| e.1));
+    }
+}
@@ -0,0 +1,178 @@
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::frequency_sensor::{Features, FeaturesF32, Summary};
+
+// every packet starts with MAGIC, then the VERSION byte and the big endian u32 length of the
+// JSON encoded message which follows
+const MAGIC: &[u8; 4] = b"VZAF";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 9;
+
+// the largest payload of a single UDP datagram over IPv4
+const MAX_PACKET: usize = 65507;
+
+/// Message is what a `FeatureServer` sends: either the full current frame of features, or the
+/// compact summary for installations with many displays.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Message {
+    Features(FeaturesF32),
+    Summary(Summary),
+}
+
+impl From<&Features> for Message {
+    fn from(features: &Features) -> Self {
+        Message::Features(features.to_f32())
+    }
+}
+
+impl From<Summary> for Message {
+    fn from(summary: Summary) -> Self {
+        Message::Summary(summary)
+    }
+}
+
+fn encode(message: &Message) -> Result<Vec<u8>> {
+    let payload = serde_json::to_vec(message)?;
+    if HEADER_LEN + payload.len() > MAX_PACKET {
+        return Err(anyhow!(
+            "message of {} bytes is too large for a packet",
+            payload.len()
+        ));
+    }
+    let mut packet = Vec::with_capacity(HEADER_LEN + payload.len());
+    packet.extend_from_slice(MAGIC);
+    packet.push(VERSION);
+    packet.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    packet.extend_from_slice(&payload);
+    Ok(packet)
+}
+
+fn decode(packet: &[u8]) -> Result<Message> {
+    if packet.len() < HEADER_LEN || &packet[..4] != MAGIC {
+        return Err(anyhow!("not a features packet"));
+    }
+    if packet[4] != VERSION {
+        return Err(anyhow!(
+            "unsupported packet version {}, expected {}",
+            packet[4],
+            VERSION
+        ));
+    }
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&packet[5..HEADER_LEN]);
+    let len = u32::from_be_bytes(len) as usize;
+    let payload = &packet[HEADER_LEN..];
+    if payload.len() != len {
+        return Err(anyhow!(
+            "partial packet: got {} of {} bytes",
+            payload.len(),
+            len
+        ));
+    }
+    Ok(serde_json::from_slice(payload)?)
+}
+
+/// FeatureServer sends features over UDP to a single address, which may be a broadcast
+/// address to reach every client on the network.
+pub struct FeatureServer {
+    socket: UdpSocket,
+    target: SocketAddr,
+}
+
+impl FeatureServer {
+    pub fn new<A: ToSocketAddrs, B: ToSocketAddrs>(bind: A, target: B) -> Result<Self> {
+        let socket = UdpSocket::bind(bind)?;
+        socket.set_broadcast(true)?;
+        let target = target
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("no target address"))?;
+        Ok(Self { socket, target })
+    }
+
+    pub fn send<M: Into<Message>>(&self, message: M) -> Result<()> {
+        let packet = encode(&message.into())?;
+        self.socket.send_to(&packet, self.target)?;
+        Ok(())
+    }
+}
+
+/// FeatureClient receives the messages sent by a `FeatureServer`.
+pub struct FeatureClient {
+    socket: UdpSocket,
+    buf: Vec<u8>,
+}
+
+impl FeatureClient {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(addr)?,
+            buf: vec![0u8; MAX_PACKET],
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// set_timeout sets how long `recv` waits for a packet. `None` waits forever.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.socket.set_read_timeout(timeout)?)
+    }
+
+    /// recv waits for the next packet and decodes it. Packets from another version of the
+    /// protocol, and truncated packets, are returned as errors.
+    pub fn recv(&mut self) -> Result<Message> {
+        let (n, _) = self.socket.recv_from(&mut self.buf)?;
+        decode(&self.buf[..n])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{decode, encode, FeatureClient, FeatureServer, Message};
+    use crate::frequency_sensor::{FrequencySensor, FrequencySensorParams};
+
+    #[test]
+    fn loopback() {
+        let mut fs = FrequencySensor::new(8, 2);
+        for i in 0..10 {
+            fs.process(
+                &mut vec![i as f64 / 10.; 8],
+                &FrequencySensorParams::default(),
+            );
+        }
+        let features = fs.get_features();
+        let centers: Vec<f64> = (0..8).map(|i| 100. * (i + 1) as f64).collect();
+
+        let mut client = FeatureClient::bind("127.0.0.1:0").unwrap();
+        client.set_timeout(Some(Duration::from_secs(5))).unwrap();
+        let server = FeatureServer::new("127.0.0.1:0", client.local_addr().unwrap()).unwrap();
+
+        server.send(features).unwrap();
+        assert_eq!(client.recv().unwrap(), Message::from(features));
+
+        let summary = features.summary(4, &centers);
+        server.send(summary.clone()).unwrap();
+        assert_eq!(client.recv().unwrap(), Message::Summary(summary));
+    }
+
+    #[test]
+    fn bad_packets() {
+        let fs = FrequencySensor::new(4, 2);
+        let packet = encode(&Message::from(fs.get_features())).unwrap();
+        assert!(decode(&packet).is_ok());
+        assert!(decode(&packet[..packet.len() - 1]).is_err());
+        assert!(decode(&packet[..4]).is_err());
+
+        let mut newer = packet.clone();
+        newer[4] += 1;
+        assert!(decode(&newer).is_err());
+    }
+}
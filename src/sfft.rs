@@ -1,26 +1,85 @@
 use std::f64::consts::PI;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 
 extern crate rustfft;
 use rustfft::num_complex::Complex;
 use rustfft::FFTplanner;
 use rustfft::FFT;
+use serde::{Deserialize, Serialize};
 
 use super::buffer::WindowBuffer;
+use crate::types::{Frame, Spectrum};
 
-/// SlidingFFT implements a sliding FFT with (1 - frame_size / fft_size) overlap.
-/// It uses a blackman-harris windowing function.
+/// WindowFunction is the window applied to the input before each transform. `BlackmanHarris`
+/// keeps the original window, which rises over the whole fft size to its peak at the newest
+/// sample. The others are the usual periodic windows, symmetric about the middle of the fft
+/// size, so that `Hann` satisfies the constant overlap-add condition at 50% and 75% overlap.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+pub enum WindowFunction {
+    #[default]
+    BlackmanHarris,
+    Hann,
+    Hamming,
+    Rectangular,
+    FlatTop,
+}
+
+impl WindowFunction {
+    fn value(&self, i: usize, n: usize) -> f64 {
+        let f = 2. * PI * i as f64 / n as f64;
+        match *self {
+            WindowFunction::BlackmanHarris => blackman_harris(i, n),
+            WindowFunction::Hann => 0.5 - 0.5 * f.cos(),
+            WindowFunction::Hamming => 0.54 - 0.46 * f.cos(),
+            WindowFunction::Rectangular => 1.,
+            WindowFunction::FlatTop => {
+                0.21557895 - 0.41663158 * f.cos() + 0.277263158 * (2. * f).cos()
+                    - 0.083578947 * (3. * f).cos()
+                    + 0.006947368 * (4. * f).cos()
+            }
+        }
+    }
+}
+
+/// SlidingFFT implements a sliding FFT with (1 - frame_size / fft_size) overlap, where
+/// frame_size is the number of samples pushed between calls to `process`. For a fixed overlap
+/// regardless of how input is pushed, see `with_hop`.
+/// It uses a blackman-harris windowing function by default, see `WindowFunction`.
 pub struct SlidingFFT {
     buffer: WindowBuffer,
     window: Vec<f64>,
+    window_function: WindowFunction,
+    magnitude_mode: MagnitudeMode,
 
     fft_size: usize,
     norm: f64,
 
-    fft: Arc<dyn FFT<f64>>,
+    // the plan of the full fft size. For an even fft size it is only needed by process_frame,
+    // so unless one is given it is planned on first use, see `full_plan`
+    fft: OnceLock<Plan>,
+    // planned on the first call to isfft
+    inverse: Option<Arc<dyn FFT<f64>>>,
+    // for an even fft size, the real input is transformed as a complex sequence of half the
+    // size, see `transform_real`
+    half: Option<Arc<dyn FFT<f64>>>,
+    twiddles: Vec<Complex<f64>>,
 
+    // preallocated fft input, filled with the windowed samples on each transform
+    samples: Vec<f64>,
+    input: Vec<Complex<f64>>,
+    half_output: Vec<Complex<f64>>,
     complex: Vec<Complex<f64>>,
-    output: Vec<f64>,
+    output: Spectrum,
+    // the output compared against by spectral_flux, empty until its first call
+    previous: Vec<f64>,
+
+    // gains of the fade in still to be applied to pushed input after a loop point
+    fade_in: Vec<f64>,
+
+    // for process_hopped, the hop size and the number of samples pushed since the end of the
+    // last hopped window
+    hop_size: Option<usize>,
+    hop_pending: usize,
 }
 
 fn blackman_harris(i: usize, n: usize) -> f64 {
@@ -32,89 +91,966 @@ fn blackman_harris(i: usize, n: usize) -> f64 {
     a0 - a1 * f.cos() + a2 * (2. * f).cos() - a3 * (3. * f).cos()
 }
 
+// raised cosine ramp rising from 0 towards 1 over len samples, excluding both end points
+fn fade_ramp(len: usize) -> Vec<f64> {
+    (0..len)
+        .map(|i| 0.5 - 0.5 * (PI * (i + 1) as f64 / (len + 1) as f64).cos())
+        .collect()
+}
+
+// cola_ripple returns the peak to peak variation of the overlap-added window, relative to its
+// mean, when consecutive windows are spaced hop samples apart.
+fn cola_ripple(window: &[f64], hop: usize) -> f64 {
+    let mut sums = vec![0f64; hop];
+    for (i, w) in window.iter().enumerate() {
+        sums[i % hop] += w;
+    }
+    let max = sums.iter().cloned().fold(f64::MIN, f64::max);
+    let min = sums.iter().cloned().fold(f64::MAX, f64::min);
+    let mean = sums.iter().sum::<f64>() / hop as f64;
+    (max - min) / mean
+}
+
+// samples where the window is below this are not recovered by isfft, as dividing by the
+// window would amplify any edits to the spectrum too much
+const MIN_INVERSE_WINDOW: f64 = 1e-3;
+
 fn log_magnitude(x: Complex<f64>) -> f64 {
     (1. + x.re * x.re + x.im * x.im).ln() * 0.5
 }
 
+/// MagnitudeMode is how `process` converts each normalized complex bin to a magnitude. `Log` is
+/// ln(1 + |x|^2) / 2, `Linear` is |x|, `Power` is |x|^2, and `Db` is 20 log10(|x| / reference),
+/// floored at -120dB so that silent bins stay finite.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+pub enum MagnitudeMode {
+    #[default]
+    Log,
+    Linear,
+    Power,
+    Db {
+        reference: f64,
+    },
+}
+
+impl MagnitudeMode {
+    fn apply(&self, x: Complex<f64>) -> f64 {
+        match *self {
+            MagnitudeMode::Log => log_magnitude(x),
+            MagnitudeMode::Linear => x.norm(),
+            MagnitudeMode::Power => x.norm_sqr(),
+            MagnitudeMode::Db { reference } => (20. * (x.norm() / reference).log10()).max(-120.),
+        }
+    }
+}
+
 impl SlidingFFT {
     pub fn new(fft_size: usize) -> SlidingFFT {
-        let mut planner = FFTplanner::new(false);
-        let fft = planner.plan_fft(fft_size);
-        let buffer = WindowBuffer::new(fft_size * 2);
+        let (fft, half) = plan(fft_size);
+        SlidingFFT::with_capacity(fft_size, fft, half, fft_size * 2, WindowFunction::default())
+    }
+
+    /// with_plan creates a SlidingFFT using an existing forward FFT plan, whose length sets the
+    /// fft size. This lets several analyzers of the same size share one plan. For an even fft
+    /// size the given plan is only used by `process_frame`, as real input is transformed with a
+    /// plan of half the size, which is then planned for each SlidingFFT; use `with_plans` to
+    /// share it too.
+    pub fn with_plan(fft: Arc<dyn FFT<f64>>) -> SlidingFFT {
+        let fft_size = fft.len();
+        let half = if fft_size & 1 == 0 {
+            Some(FFTplanner::new(false).plan_fft(fft_size / 2))
+        } else {
+            None
+        };
+        SlidingFFT::with_capacity(
+            fft_size,
+            Some(fft),
+            half,
+            fft_size * 2,
+            WindowFunction::default(),
+        )
+    }
+
+    /// with_plans is like `with_plan`, but also takes the forward plan of half the fft size,
+    /// which must be even, that real input is transformed with. See `get_half_plan`.
+    pub fn with_plans(fft: Arc<dyn FFT<f64>>, half: Arc<dyn FFT<f64>>) -> SlidingFFT {
+        let fft_size = fft.len();
+        if fft_size & 1 == 1 || half.len() != fft_size / 2 {
+            panic!("half plan must be of half the fft size");
+        }
+        SlidingFFT::with_capacity(
+            fft_size,
+            Some(fft),
+            Some(half),
+            fft_size * 2,
+            WindowFunction::default(),
+        )
+    }
+
+    /// with_window creates a SlidingFFT which applies the given window function.
+    pub fn with_window(fft_size: usize, window_function: WindowFunction) -> SlidingFFT {
+        let (fft, half) = plan(fft_size);
+        SlidingFFT::with_capacity(fft_size, fft, half, fft_size * 2, window_function)
+    }
+
+    /// with_overlap creates a SlidingFFT for analysis where each window overlaps the previous one
+    /// `overlap` times, i.e. with a hop of `fft_size / overlap` samples between windows. The
+    /// input buffer holds one window plus one hop, so frames pushed between calls to `process`
+    /// must not exceed that.
+    pub fn with_overlap(fft_size: usize, overlap: usize) -> SlidingFFT {
+        if overlap == 0 || overlap > fft_size {
+            panic!("overlap must be between 1 and fft_size");
+        }
+        let (fft, half) = plan(fft_size);
+        let hop = fft_size / overlap;
+        SlidingFFT::with_capacity(
+            fft_size,
+            fft,
+            half,
+            fft_size + hop,
+            WindowFunction::default(),
+        )
+    }
+
+    /// with_hop creates a SlidingFFT for `process_hopped`, which produces a frame for every
+    /// `hop_size` samples pushed, however the input is split into frames. The input buffer holds
+    /// one window plus one more window and hop of pending input.
+    pub fn with_hop(fft_size: usize, hop_size: usize) -> SlidingFFT {
+        if hop_size == 0 {
+            panic!("hop size must be positive");
+        }
+        let (fft, half) = plan(fft_size);
+        let mut sfft = SlidingFFT::with_capacity(
+            fft_size,
+            fft,
+            half,
+            2 * fft_size + hop_size,
+            WindowFunction::default(),
+        );
+        sfft.hop_size = Some(hop_size);
+        sfft
+    }
+
+    /// with_history_capacity creates a SlidingFFT whose input buffer holds `history_capacity`
+    /// samples, which must be at least fft_size, instead of the default of twice the fft size.
+    /// Up to that many of the most recent samples can then be read back with `get_history`,
+    /// e.g. for analysis at several resolutions.
+    pub fn with_history_capacity(fft_size: usize, history_capacity: usize) -> SlidingFFT {
+        let (fft, half) = plan(fft_size);
+        SlidingFFT::with_capacity(
+            fft_size,
+            fft,
+            half,
+            history_capacity,
+            WindowFunction::default(),
+        )
+    }
+
+    // with_capacity takes the plan of the full fft size, which is planned on first use if
+    // absent, and for an even fft size, the plan of half of it
+    fn with_capacity(
+        fft_size: usize,
+        fft: Option<Plan>,
+        half: Option<Plan>,
+        capacity: usize,
+        window_function: WindowFunction,
+    ) -> SlidingFFT {
+        if fft.as_ref().is_some_and(|f| f.is_inverse())
+            || half.as_ref().is_some_and(|h| h.is_inverse())
+        {
+            panic!("sliding fft requires a forward fft plan");
+        }
+        if (fft_size & 1 == 0) != half.is_some() {
+            panic!("an even fft size requires a plan of half of it");
+        }
+        if capacity < fft_size {
+            panic!("window buffer capacity must be at least fft_size");
+        }
+        let buffer = WindowBuffer::new(capacity);
 
         let window = (0..fft_size)
-            .map(|i| blackman_harris(i, fft_size))
+            .map(|i| window_function.value(i, fft_size))
             .collect();
 
         let complex = vec![Complex::from(0f64); fft_size];
-        let output = vec![0f64; fft_size / 2];
+        let output = Spectrum::new(fft_size / 2);
+
+        let twiddles = (0..=fft_size / 2)
+            .map(|k| Complex::from_polar(1., -2. * PI * k as f64 / fft_size as f64))
+            .collect();
 
         SlidingFFT {
             buffer,
             window,
+            window_function,
+            magnitude_mode: MagnitudeMode::default(),
             fft_size,
             norm: 1. / (fft_size as f64),
+            half,
+            twiddles,
+            samples: vec![0f64; fft_size],
+            input: complex.clone(),
+            half_output: vec![Complex::from(0f64); fft_size / 2],
             complex,
             output,
-            fft,
+            previous: Vec::new(),
+            fft: fft.map(OnceLock::from).unwrap_or_default(),
+            inverse: None,
+            fade_in: Vec::new(),
+            hop_size: None,
+            hop_pending: 0,
+        }
+    }
+
+    /// resize changes the fft size, planning a new FFT and rebuilding the window and buffers.
+    /// The window function, magnitude mode and hop size are kept, along with the amount of
+    /// input buffered beyond one window, but all buffered input is discarded, so the next
+    /// frames are of zero padded input. `output_size` changes to half the new fft size, so
+    /// anything sized from it, such as a `Bucketer`, must be rebuilt too.
+    pub fn resize(&mut self, fft_size: usize) {
+        let extra = self.buffer.capacity() - self.fft_size;
+        let (fft, half) = plan(fft_size);
+        let mut sfft =
+            SlidingFFT::with_capacity(fft_size, fft, half, fft_size + extra, self.window_function);
+        sfft.magnitude_mode = self.magnitude_mode;
+        sfft.hop_size = self.hop_size;
+        *self = sfft;
+    }
+
+    /// reset discards all buffered input, and the state of `loop_point`, `process_hopped` and
+    /// `spectral_flux`, keeping the configuration.
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.fade_in.clear();
+        self.hop_pending = 0;
+        self.previous.clear();
+    }
+
+    pub fn push_input(&mut self, frame: &Frame) {
+        self.push_slice(frame);
+    }
+
+    /// push_slice is like `push_input` for a plain slice of samples.
+    pub fn push_slice(&mut self, frame: &[f64]) {
+        self.hop_pending += frame.len();
+        if self.fade_in.is_empty() {
+            self.buffer.push(frame);
+            return;
+        }
+        let n = self.fade_in.len().min(frame.len());
+        let mut faded = frame.to_vec();
+        for (x, g) in faded.iter_mut().zip(self.fade_in.drain(..n)) {
+            *x *= g;
         }
+        self.buffer.push(&faded);
     }
 
-    pub fn push_input(&mut self, frame: &Vec<f64>) -> () {
-        self.buffer.push(frame);
+    /// loop_point marks a discontinuity in the input, such as the seam of a looping clip, which
+    /// is about to be pushed. The last `fade_len` buffered samples are faded out and the next
+    /// `fade_len` samples pushed are faded in, so the seam doesn't show up as a broadband
+    /// transient.
+    pub fn loop_point(&mut self, fade_len: usize) {
+        let mut fade_out = fade_ramp(fade_len);
+        fade_out.reverse();
+        self.buffer.scale_tail(&fade_out);
+        self.fade_in = fade_ramp(fade_len);
     }
 
     /// process returns the log magnitude of the fft of the most recent fft_size data.
-    pub fn process(&mut self) -> &Vec<f64> {
-        let fft_frame = self.buffer.get(self.fft_size);
+    pub fn process(&mut self) -> &Spectrum {
+        self.transform();
+        self.update_output()
+    }
 
-        let mut input: Vec<Complex<f64>> = fft_frame
-            .iter()
-            .enumerate()
-            .map(|(i, x)| x * self.window[i])
-            .map(Complex::from)
-            .collect();
+    /// process_hopped returns the magnitudes of the next window, which ends a whole number of
+    /// hops after the first sample pushed, once a full hop of samples has been pushed past the
+    /// previous one. Otherwise it returns `None`. When more than one hop has been pushed since
+    /// the last call it should be called again until it returns `None`. Windows which are no
+    /// longer in the input buffer are skipped.
+    pub fn process_hopped(&mut self) -> Option<&Spectrum> {
+        let hop = match self.hop_size {
+            Some(hop) => hop,
+            None => panic!("process_hopped requires a SlidingFFT created with with_hop"),
+        };
+        if self.hop_pending < hop {
+            return None;
+        }
+        let max_delay = self.buffer.capacity() - self.fft_size;
+        while self.hop_pending - hop > max_delay {
+            self.hop_pending -= hop;
+        }
+        self.hop_pending -= hop;
+        self.transform_delayed(self.hop_pending);
+        Some(self.update_output())
+    }
 
-        self.fft.process(&mut input, &mut self.complex);
+    /// process_frame returns the log magnitude of the fft of a caller supplied frame of
+    /// fft_size samples. The frame bypasses the input buffer and is not windowed, so it should
+    /// already be windowed by the caller.
+    pub fn process_frame(&mut self, frame: &[Complex<f64>]) -> &Spectrum {
+        if frame.len() != self.fft_size {
+            panic!("frame size must equal fft_size");
+        }
+        let mut input = frame.to_vec();
+        let fft = self.full_plan().clone();
+        fft.process(&mut input, &mut self.complex);
+        self.update_output()
+    }
 
+    fn update_output(&mut self) -> &Spectrum {
         for i in 0..self.fft_size / 2 {
-            self.output[i] = log_magnitude(self.complex[i] * self.norm);
+            self.output[i] = self.magnitude_mode.apply(self.complex[i] * self.norm);
         }
 
         &self.output
     }
 
+    /// process_complex returns the unnormalized complex fft of the most recent fft_size data
+    /// after windowing. It shares its buffer with `process`, so the result is overwritten by the
+    /// next call to either.
+    pub fn process_complex(&mut self) -> &Vec<Complex<f64>> {
+        self.transform();
+        &self.complex
+    }
+
+    /// phase returns the phase in radians of each of the `output_size` bins of the most recent
+    /// transform, from `process`, `process_frame` or `process_complex`.
+    pub fn phase(&self) -> Vec<f64> {
+        self.complex[..self.output.len()]
+            .iter()
+            .map(|c| c.im.atan2(c.re))
+            .collect()
+    }
+
+    fn transform(&mut self) {
+        self.transform_delayed(0);
+    }
+
+    // transform_delayed transforms the window which ends delay samples before the newest one
+    fn transform_delayed(&mut self, delay: usize) {
+        if delay == 0 {
+            self.buffer.get_into(&mut self.samples);
+        } else {
+            self.buffer.get_into_delayed(&mut self.samples, delay);
+        }
+        for (x, w) in self.samples.iter_mut().zip(self.window.iter()) {
+            *x *= w;
+        }
+
+        if self.half.is_some() {
+            self.transform_real();
+            return;
+        }
+        for (c, &x) in self.input.iter_mut().zip(self.samples.iter()) {
+            *c = Complex::from(x);
+        }
+        let fft = self.full_plan().clone();
+        fft.process(&mut self.input, &mut self.complex);
+    }
+
+    // transform_real computes the fft of the real, even length samples with a complex fft of
+    // half the length, by packing the even samples into the real part and the odd samples into
+    // the imaginary part, then separating the two spectra. The upper half of the output is
+    // filled in by conjugate symmetry.
+    fn transform_real(&mut self) {
+        let m = self.fft_size / 2;
+        let half = match &self.half {
+            Some(half) => half,
+            None => return,
+        };
+        for (c, pair) in self.input[..m].iter_mut().zip(self.samples.chunks_exact(2)) {
+            *c = Complex::new(pair[0], pair[1]);
+        }
+        half.process(&mut self.input[..m], &mut self.half_output);
+
+        let z = &self.half_output;
+        // the dc and nyquist bins are real
+        self.complex[0] = Complex::from(z[0].re + z[0].im);
+        self.complex[m] = Complex::from(z[0].re - z[0].im);
+        for k in 1..m {
+            let zk = z[k];
+            let zc = z[m - k].conj();
+            let even = (zk + zc) * 0.5;
+            let odd = (zk - zc) * Complex::new(0., -0.5);
+            let x = even + self.twiddles[k] * odd;
+            self.complex[k] = x;
+            self.complex[self.fft_size - k] = x.conj();
+        }
+    }
+
+    /// isfft resynthesizes the fft_size samples of a window from its complex spectrum, as
+    /// returned by `process_complex` and possibly edited, by inverting the fft and dividing out
+    /// the window. With a `Rectangular` window the input is reconstructed to within rounding
+    /// error, around 1e-15 relative to the signal. Other windows are divided out wherever they
+    /// are at least 1e-3, which amplifies the error of those samples by up to 1 / window, and the
+    /// remaining samples, e.g. the first sample of a `Hann` window, are returned as 0.
+    pub fn isfft(&mut self, spectrum: &[Complex<f64>]) -> Vec<f64> {
+        if spectrum.len() != self.fft_size {
+            panic!("spectrum size must equal fft_size");
+        }
+        let fft_size = self.fft_size;
+        let inverse = self
+            .inverse
+            .get_or_insert_with(|| FFTplanner::new(true).plan_fft(fft_size));
+        let mut input = spectrum.to_vec();
+        let mut output = vec![Complex::from(0f64); fft_size];
+        inverse.process(&mut input, &mut output);
+        output
+            .iter()
+            .zip(self.window.iter())
+            .map(|(x, &w)| {
+                if w < MIN_INVERSE_WINDOW {
+                    0.
+                } else {
+                    x.re * self.norm / w
+                }
+            })
+            .collect()
+    }
+
+    /// estimate_peak returns the frequency in Hz and magnitude of the largest peak of the most
+    /// recent output, interpolated between bins by fitting a parabola through the largest bin and
+    /// its two neighbors. The fit is most accurate for the log magnitudes of the default
+    /// `MagnitudeMode`. A peak in the first or last bin is returned at the bin's center.
+    pub fn estimate_peak(&self, sample_rate: u32) -> (f64, f64) {
+        let bin_width = sample_rate as f64 / self.fft_size as f64;
+        let (k, &b) = match self
+            .output
+            .iter()
+            .enumerate()
+            .max_by(|(_, x), (_, y)| x.total_cmp(y))
+        {
+            Some(peak) => peak,
+            None => return (0., 0.),
+        };
+        if k == 0 || k == self.output.len() - 1 {
+            return (k as f64 * bin_width, b);
+        }
+        let (a, c) = (self.output[k - 1], self.output[k + 1]);
+        let curvature = a - 2. * b + c;
+        if curvature == 0. {
+            return (k as f64 * bin_width, b);
+        }
+        let p = 0.5 * (a - c) / curvature;
+        ((k as f64 + p) * bin_width, b - 0.25 * (a - c) * p)
+    }
+
+    /// spectral_flux returns the sum of the increases in each bin of the most recent output
+    /// since the output of the previous call, which makes a simple onset detection signal when
+    /// called after each `process`. The first call returns 0.
+    pub fn spectral_flux(&mut self) -> f64 {
+        if self.previous.is_empty() {
+            self.previous = self.output.to_vec();
+            return 0.;
+        }
+        let flux = self
+            .output
+            .iter()
+            .zip(self.previous.iter())
+            .map(|(x, p)| (x - p).max(0.))
+            .sum();
+        self.previous.copy_from_slice(&self.output);
+        flux
+    }
+
+    /// get_history returns the `size` most recently pushed samples, oldest first. `size` must not
+    /// exceed the history capacity.
+    pub fn get_history(&self, size: usize) -> Vec<f64> {
+        self.buffer.get(size)
+    }
+
+    /// cola_ripple returns how far the window, overlap-added at the given hop, is from a
+    /// constant, as its peak to peak variation relative to its mean. Analysis followed by
+    /// resynthesis at that hop is free of amplitude modulation when this is zero.
+    pub fn cola_ripple(&self, hop: usize) -> f64 {
+        if hop == 0 || hop > self.fft_size {
+            panic!("hop must be between 1 and fft_size");
+        }
+        cola_ripple(&self.window, hop)
+    }
+
+    /// is_cola returns whether the window satisfies the constant overlap-add condition at the
+    /// given hop. See `cola_ripple`.
+    pub fn is_cola(&self, hop: usize) -> bool {
+        self.cola_ripple(hop) < 1e-9
+    }
+
+    /// is_primed returns whether a full fft_size of input has been pushed.
+    pub fn is_primed(&self) -> bool {
+        self.buffer.is_primed(self.fft_size)
+    }
+
     pub fn output_size(&self) -> usize {
         self.output.len()
     }
+
+    pub fn get_magnitude_mode(&self) -> MagnitudeMode {
+        self.magnitude_mode
+    }
+
+    /// set_magnitude_mode sets how `process` computes the magnitude of each bin.
+    pub fn set_magnitude_mode(&mut self, mode: MagnitudeMode) {
+        self.magnitude_mode = mode;
+    }
+
+    pub fn get_window_function(&self) -> WindowFunction {
+        self.window_function
+    }
+
+    // full_plan returns the plan of the full fft size, planning it on the first call
+    fn full_plan(&self) -> &Plan {
+        self.fft
+            .get_or_init(|| FFTplanner::new(false).plan_fft(self.fft_size))
+    }
+
+    /// get_plan returns the FFT plan, which can be passed to `with_plan` to share it. For an
+    /// even fft size it is planned on the first call, unless it was given to `with_plan`.
+    pub fn get_plan(&self) -> Arc<dyn FFT<f64>> {
+        self.full_plan().clone()
+    }
+
+    /// get_half_plan returns the plan of half the fft size, which can be passed to `with_plans`
+    /// along with `get_plan` to share both. It is `None` for an odd fft size.
+    pub fn get_half_plan(&self) -> Option<Arc<dyn FFT<f64>>> {
+        self.half.clone()
+    }
+}
+
+type Plan = Arc<dyn FFT<f64>>;
+
+// plan returns the forward plans a SlidingFFT of fft_size needs up front: for an even size only
+// the plan of half of it, which real input is transformed with, and otherwise the full plan
+fn plan(fft_size: usize) -> (Option<Plan>, Option<Plan>) {
+    let mut planner = FFTplanner::new(false);
+    if fft_size & 1 == 0 {
+        (None, Some(planner.plan_fft(fft_size / 2)))
+    } else {
+        (Some(planner.plan_fft(fft_size)), None)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::SlidingFFT;
+    use super::{blackman_harris, cola_ripple, MagnitudeMode, SlidingFFT, WindowFunction};
+    use crate::types::Frame;
+    use rustfft::num_complex::Complex;
+    use rustfft::FFTplanner;
     use std::f64::consts::PI;
+    use std::sync::Arc;
 
     #[test]
     fn it_works() {
         let mut sfft = SlidingFFT::new(16);
-        let d = (0..16)
+        let d: Frame = (0..16)
             .map(|i| (i as f64 * 4. * PI / 16.).cos() + 1.)
             .collect();
         sfft.push_input(&d);
         let out = sfft.process();
+        // this value is kind of just chosen assuming this is basically correct
+        let expected = vec![
+            0.05165678466904211,
+            0.00955023887645858,
+            0.013055105778072026,
+            0.0148816897701956,
+            0.005285894136972388,
+            0.0031631811918354604,
+            0.0023867968234884346,
+            0.0020535130293983035,
+        ];
+        for (a, b) in out.iter().zip(expected.iter()) {
+            assert!((a - b).abs() < 1e-15, "{:?} != {:?}", out, expected);
+        }
+    }
+
+    #[test]
+    fn shared_plan() {
+        let plan = FFTplanner::new(false).plan_fft(16);
+        let mut a = SlidingFFT::with_plan(plan.clone());
+        let mut b = SlidingFFT::with_plan(a.get_plan());
+        assert!(Arc::ptr_eq(&a.get_plan(), &plan));
+        assert!(Arc::ptr_eq(&b.get_plan(), &plan));
+
+        let half = a.get_half_plan().unwrap();
+        let mut c = SlidingFFT::with_plans(a.get_plan(), half.clone());
+        assert!(Arc::ptr_eq(&c.get_half_plan().unwrap(), &half));
+        assert!(!Arc::ptr_eq(&b.get_half_plan().unwrap(), &half));
+
+        let d: Frame = (0..16)
+            .map(|i| (i as f64 * 4. * PI / 16.).cos() + 1.)
+            .collect();
+        a.push_input(&d);
+        b.push_input(&d);
+        c.push_input(&d);
+        let expected = a.process().clone();
+        assert_eq!(&expected, b.process());
+        assert_eq!(&expected, c.process());
+    }
+
+    fn naive_dft(input: &[f64]) -> Vec<Complex<f64>> {
+        let n = input.len();
+        (0..n)
+            .map(|k| {
+                input
+                    .iter()
+                    .enumerate()
+                    .map(|(j, &x)| {
+                        let theta = -2. * PI * (k * j) as f64 / n as f64;
+                        Complex::new(x * theta.cos(), x * theta.sin())
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_naive_dft() {
+        // a small lcg keeps the "random" input deterministic
+        let mut seed = 12345u64;
+        let mut random = move || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 11) as f64 / (1u64 << 53) as f64 * 2. - 1.
+        };
+
+        for &size in &[2, 4, 8, 15, 16, 64] {
+            let mut sfft = SlidingFFT::new(size);
+            let frame: Frame = (0..size).map(|_| random()).collect();
+            sfft.push_input(&frame);
+
+            let windowed: Vec<f64> = frame
+                .iter()
+                .enumerate()
+                .map(|(i, x)| x * blackman_harris(i, size))
+                .collect();
+            let expected = naive_dft(&windowed);
+
+            let out = sfft.process_complex();
+            assert_eq!(out.len(), size);
+            for (a, b) in out.iter().zip(expected.iter()) {
+                assert!((a - b).norm() < 1e-10, "size {}: {} != {}", size, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn high_overlap_keeps_every_sample() {
+        let (fft_size, overlap) = (64, 8);
+        let hop = fft_size / overlap;
+        let mut sfft = SlidingFFT::with_overlap(fft_size, overlap);
+
+        let stream: Vec<f64> = (0..fft_size * 10).map(|i| (i as f64 * 0.1).sin()).collect();
+        for (n, block) in stream.chunks(hop).enumerate() {
+            sfft.push_input(&block.to_vec().into());
+            sfft.process();
+
+            let end = (n + 1) * hop;
+            if end >= fft_size {
+                assert_eq!(sfft.buffer.get(fft_size), &stream[end - fft_size..end]);
+            }
+        }
+    }
+
+    #[test]
+    fn process_frame() {
+        let mut a = SlidingFFT::new(16);
+        let mut b = SlidingFFT::new(16);
+        let d: Frame = (0..16)
+            .map(|i| (i as f64 * 4. * PI / 16.).cos() + 1.)
+            .collect();
+
+        a.push_input(&d);
+        let windowed: Vec<Complex<f64>> = d
+            .iter()
+            .enumerate()
+            .map(|(i, x)| Complex::from(x * blackman_harris(i, 16)))
+            .collect();
+        let expected = b.process_frame(&windowed).clone();
+        for (x, y) in a.process().iter().zip(expected.iter()) {
+            assert!((x - y).abs() < 1e-15);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn process_frame_checks_size() {
+        SlidingFFT::new(16).process_frame(&[Complex::from(0.); 8]);
+    }
+
+    #[test]
+    fn history_capacity() {
+        let mut sfft = SlidingFFT::with_history_capacity(16, 100);
+        let input: Vec<f64> = (0..250).map(f64::from).collect();
+        for chunk in input.chunks(30) {
+            sfft.push_input(&chunk.to_vec().into());
+        }
+
+        for &size in &[1, 16, 64, 100] {
+            assert_eq!(sfft.get_history(size), input[250 - size..].to_vec());
+        }
+        assert_eq!(sfft.process().len(), 8);
+    }
+
+    #[test]
+    #[should_panic]
+    fn history_capacity_below_fft_size() {
+        SlidingFFT::with_history_capacity(16, 15);
+    }
+
+    #[test]
+    fn cola() {
+        // a periodic hann window overlap-adds to a constant at 50% and 75% overlap
+        let hann: Vec<f64> = (0..64)
+            .map(|i| 0.5 - 0.5 * (2. * PI * i as f64 / 64.).cos())
+            .collect();
+        assert!(cola_ripple(&hann, 32) < 1e-12);
+        assert!(cola_ripple(&hann, 16) < 1e-12);
+        assert!(cola_ripple(&hann, 24) > 0.01);
+
+        let sfft = SlidingFFT::new(64);
+        assert!(!sfft.is_cola(24));
+        assert!(sfft.cola_ripple(24) > 0.01);
+    }
+
+    #[test]
+    fn window_function() {
+        assert_eq!(
+            SlidingFFT::new(64).get_window_function(),
+            WindowFunction::BlackmanHarris
+        );
+
+        let hann = SlidingFFT::with_window(64, WindowFunction::Hann);
+        assert_eq!(hann.get_window_function(), WindowFunction::Hann);
+        assert!(hann.is_cola(32));
+        assert!(hann.is_cola(16));
+        assert!(SlidingFFT::with_window(64, WindowFunction::Rectangular).is_cola(8));
+
+        // a tone between bins leaks far less through the flat top window than the rectangular
+        let tone: Frame = (0..64)
+            .map(|i| (2. * PI * 10.5 * i as f64 / 64.).sin())
+            .collect();
+        let leakage = |window_function| {
+            let mut sfft = SlidingFFT::with_window(64, window_function);
+            sfft.push_input(&tone);
+            let out = sfft.process();
+            out[24..].iter().sum::<f64>() / out[8..13].iter().sum::<f64>()
+        };
+        assert!(leakage(WindowFunction::FlatTop) < 0.1 * leakage(WindowFunction::Rectangular));
+    }
+
+    #[test]
+    fn phase() {
+        let mut sfft = SlidingFFT::with_window(16, WindowFunction::Rectangular);
+        let d: Frame = (0..16)
+            .map(|i| (2. * PI * 4. * i as f64 / 16. + 0.5).cos())
+            .collect();
+        sfft.push_input(&d);
+        let magnitude = sfft.process().clone();
+        let phase = sfft.phase();
+        assert_eq!(phase.len(), 8);
+        assert!((phase[4] - 0.5).abs() < 1e-12);
+
+        // the complex result is the same transform
+        let complex = sfft.process_complex();
+        assert!((complex[4].arg() - 0.5).abs() < 1e-12);
+        assert_eq!(sfft.process(), &magnitude);
+    }
+
+    #[test]
+    fn magnitude_mode() {
+        let mut sfft = SlidingFFT::with_window(16, WindowFunction::Rectangular);
+        assert_eq!(sfft.get_magnitude_mode(), MagnitudeMode::Log);
+        let d: Frame = (0..16)
+            .map(|i| 2. * (2. * PI * 4. * i as f64 / 16.).cos())
+            .collect();
+        sfft.push_input(&d);
+
+        // the normalized bin of a cosine of amplitude 2 has magnitude 1
+        sfft.set_magnitude_mode(MagnitudeMode::Linear);
+        let linear = sfft.process().clone();
+        assert!((linear[4] - 1.).abs() < 1e-12);
+        sfft.set_magnitude_mode(MagnitudeMode::Power);
+        assert!((sfft.process()[4] - 1.).abs() < 1e-12);
+
+        sfft.set_magnitude_mode(MagnitudeMode::Db { reference: 0.5 });
+        let db = sfft.process().clone();
+        assert!((db[4] - 20. * 2f64.log10()).abs() < 1e-9);
+        assert_eq!(db[3], -120.);
+    }
+
+    #[test]
+    fn process_hopped() {
+        let d: Vec<f64> = (0..200)
+            .map(|i| (i as f64 * 0.3).sin() + (i as f64 * 0.05).cos())
+            .collect();
+
+        // the frames don't depend on how the input is split up
+        let hopped = |chunks: &[usize]| {
+            let mut sfft = SlidingFFT::with_hop(32, 12);
+            let mut frames = Vec::new();
+            let mut rest = &d[..];
+            for &n in chunks.iter().cycle() {
+                if rest.is_empty() {
+                    break;
+                }
+                let (chunk, r) = rest.split_at(n.min(rest.len()));
+                rest = r;
+                sfft.push_slice(chunk);
+                while let Some(spectrum) = sfft.process_hopped() {
+                    frames.push(spectrum.clone());
+                }
+            }
+            frames
+        };
+        let frames = hopped(&[7, 13, 1, 30]);
+        assert_eq!(frames.len(), 200 / 12);
+        assert_eq!(frames, hopped(&[12]));
+        assert_eq!(frames, hopped(&[5, 40]));
+
+        // each frame is the window ending on a hop boundary
+        for (k, frame) in frames.iter().enumerate() {
+            let mut sfft = SlidingFFT::new(32);
+            for chunk in d[..(k + 1) * 12].chunks(32) {
+                sfft.push_slice(chunk);
+            }
+            assert_eq!(sfft.process(), frame);
+        }
+    }
+
+    #[test]
+    fn process_does_not_allocate() {
+        use crate::testutil::alloc::count_allocations;
+
+        let mut sfft = SlidingFFT::new(256);
+        let frame: Frame = (0..64).map(|i| (i as f64 * 0.2).sin()).collect();
+        let allocations = count_allocations(|| {
+            for _ in 0..1000 {
+                sfft.push_input(&frame);
+                sfft.process();
+            }
+        });
+        assert_eq!(allocations, 0);
+    }
+
+    #[test]
+    fn resize() {
+        let mut sfft = SlidingFFT::with_window(16, WindowFunction::Hann);
+        sfft.set_magnitude_mode(MagnitudeMode::Linear);
+        let d: Frame = (0..32).map(|i| (i as f64 * 0.7).sin()).collect();
+        sfft.push_input(&d);
+        sfft.process();
+
+        sfft.resize(32);
+        assert_eq!(sfft.output_size(), 16);
+        assert_eq!(sfft.get_window_function(), WindowFunction::Hann);
+        assert_eq!(sfft.get_magnitude_mode(), MagnitudeMode::Linear);
+        assert!(!sfft.is_primed());
+
+        let mut fresh = SlidingFFT::with_window(32, WindowFunction::Hann);
+        fresh.set_magnitude_mode(MagnitudeMode::Linear);
+        sfft.push_input(&d);
+        fresh.push_input(&d);
+        assert_eq!(sfft.process(), fresh.process());
+    }
+
+    #[test]
+    fn isfft() {
+        let d: Frame = (0..32)
+            .map(|i| (2. * PI * 3. * i as f64 / 32.).sin() + 0.5 * (i as f64 * 0.9).cos())
+            .collect();
+
+        let mut sfft = SlidingFFT::with_window(32, WindowFunction::Rectangular);
+        sfft.push_input(&d);
+        let spectrum = sfft.process_complex().clone();
+        for (a, b) in sfft.isfft(&spectrum).iter().zip(d.iter()) {
+            assert!((a - b).abs() < 1e-12, "{} != {}", a, b);
+        }
+
+        let mut sfft = SlidingFFT::with_window(32, WindowFunction::Hann);
+        sfft.push_input(&d);
+        let spectrum = sfft.process_complex().clone();
+        let out = sfft.isfft(&spectrum);
+        assert_eq!(out[0], 0.);
+        for (a, b) in out[1..].iter().zip(d[1..].iter()) {
+            assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+        }
+
+        // removing a tone's bins removes it from the resynthesized signal
+        let tone: Frame = (0..32)
+            .map(|i| (2. * PI * 3. * i as f64 / 32.).sin() + 1.)
+            .collect();
+        let mut sfft = SlidingFFT::with_window(32, WindowFunction::Rectangular);
+        sfft.push_input(&tone);
+        let mut spectrum = sfft.process_complex().clone();
+        spectrum[3] = Complex::from(0.);
+        spectrum[29] = Complex::from(0.);
+        for x in sfft.isfft(&spectrum) {
+            assert!((x - 1.).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn estimate_peak() {
+        let sample_rate = 1024;
+        let mut sfft = SlidingFFT::with_window(64, WindowFunction::Hann);
+        let tone = |f: f64| -> Frame {
+            (0..64)
+                .map(|i| 100. * (2. * PI * f * i as f64 / sample_rate as f64).sin())
+                .collect()
+        };
+
+        // bins are 16 Hz wide
+        sfft.push_input(&tone(165.));
+        let peak = sfft.process()[10];
+        let (f, magnitude) = sfft.estimate_peak(sample_rate);
+        assert!((f - 165.).abs() < 1., "{}", f);
+        assert!(magnitude >= peak);
+
+        // a peak at the edge falls back to the bin center
+        sfft.push_slice(&[1.; 64]);
+        sfft.process();
+        assert_eq!(sfft.estimate_peak(sample_rate).0, 0.);
+    }
+
+    #[test]
+    fn spectral_flux() {
+        let mut sfft = SlidingFFT::new(32);
+        let tone: Frame = (0..32).map(|i| (i as f64 * 0.8).sin()).collect();
+        sfft.push_input(&tone);
+        sfft.process();
+        assert_eq!(sfft.spectral_flux(), 0.);
+
+        // a steady tone has no flux
+        sfft.push_input(&tone);
+        sfft.process();
+        assert!(sfft.spectral_flux().abs() < 1e-12);
+
+        // an onset has a large positive flux, but its decay has none
+        let loud: Frame = tone.iter().map(|x| 4. * x).collect();
+        sfft.push_input(&loud);
+        sfft.process();
+        assert!(sfft.spectral_flux() > 0.1);
+        sfft.push_input(&tone);
+        sfft.process();
+        assert_eq!(sfft.spectral_flux(), 0.);
+    }
+
+    #[test]
+    fn plans_full_size_lazily() {
+        let mut sfft = SlidingFFT::new(16);
+        let d: Frame = (0..16).map(|i| (i as f64).sin()).collect();
+        sfft.push_input(&d);
+        sfft.process();
+        assert!(sfft.fft.get().is_none());
+
+        let frame: Vec<Complex<f64>> = d.iter().map(|&x| Complex::from(x)).collect();
+        let expected = sfft.process_frame(&frame).clone();
+        assert!(sfft.fft.get().is_some());
         assert_eq!(
-            out,
-            // this value is kind of just chosen assuming this is basically correct
-            &vec![
-                0.05165678466904211,
-                0.00955023887645858,
-                0.013055105778072026,
-                0.0148816897701956,
-                0.005285894136972388,
-                0.0031631811918354604,
-                0.0023867968234884346,
-                0.0020535130293983035
-            ],
+            &expected,
+            SlidingFFT::with_plan(sfft.get_plan()).process_frame(&frame)
         );
+
+        assert!(SlidingFFT::new(15).fft.get().is_some());
     }
 }
@@ -3,9 +3,24 @@ use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 
 pub use cpal::Stream;
 
+/// i16_block_stats returns the RMS of a block of integer samples, normalized so that a full
+/// scale square wave has an RMS of 1, along with its peak magnitude. The sum of squares is
+/// accumulated as an integer so no headroom is lost before the input is converted.
+pub fn i16_block_stats(data: &[i16]) -> (f64, i16) {
+    if data.is_empty() {
+        return (0., 0);
+    }
+    let sum_sq: u64 = data.iter().map(|&x| (x as i64 * x as i64) as u64).sum();
+    let peak = data.iter().map(|x| x.saturating_abs()).max().unwrap_or(0);
+    let rms = (sum_sq as f64 / data.len() as f64).sqrt() / 32768.;
+    (rms, peak)
+}
+
 /// Source is an audio source
 pub struct Source {
     device: cpal::Device,
+    // whether the device is an output device captured in loopback mode
+    loopback: bool,
 }
 
 impl<'a> Source {
@@ -27,7 +42,54 @@ impl<'a> Source {
                 .ok_or_else(|| anyhow!("could not get default input"))
         }?;
 
-        Ok(Self { device })
+        Ok(Self {
+            device,
+            loopback: false,
+        })
+    }
+
+    /// output_loopback creates a source that captures whatever is playing on an output device,
+    /// or on the default output device if `select_device` is `None`.
+    ///
+    /// Loopback capture of output devices is only supported by the WASAPI host on Windows. On
+    /// other platforms an error is returned; with PulseAudio, the output can be captured instead
+    /// by selecting its monitor source as the input device, e.g. with `pavucontrol`, and using
+    /// `Source::new`. WASAPI does not support fixed buffer sizes in loopback mode, so the buffer
+    /// size given to `get_stream` is ignored and the host picks one.
+    pub fn output_loopback(select_device: Option<&str>) -> Result<Self> {
+        if !cfg!(target_os = "windows") {
+            return Err(anyhow!(
+                "loopback capture of output devices is only supported on windows; \
+                 select a monitor source of the output as the input device instead"
+            ));
+        }
+        let host = cpal::default_host();
+
+        let device = if let Some(device_name) = select_device {
+            let devices: Vec<cpal::Device> = host
+                .output_devices()
+                .map_err(|e| anyhow!("could not get audio output devices: {}", e))?
+                .collect();
+            let device_names: Vec<String> = devices.iter().flat_map(|d| d.name()).collect();
+            devices
+                .into_iter()
+                .find(|d| d.name().map(|name| name == device_name).unwrap_or(false))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "no output device with name '{}' was found. devices: {:?}",
+                        device_name,
+                        device_names
+                    )
+                })
+        } else {
+            host.default_output_device()
+                .ok_or_else(|| anyhow!("could not get default output"))
+        }?;
+
+        Ok(Self {
+            device,
+            loopback: true,
+        })
     }
 
     pub fn get_stream<T: 'static + cpal::Sample>(
@@ -38,7 +100,11 @@ impl<'a> Source {
         handle_stream: Box<dyn Fn(&[T]) -> () + Send>,
     ) -> Result<Stream> {
         let config = cpal::StreamConfig {
-            buffer_size: cpal::BufferSize::Fixed(buffer_size),
+            buffer_size: if self.loopback {
+                cpal::BufferSize::Default
+            } else {
+                cpal::BufferSize::Fixed(buffer_size)
+            },
             channels,
             sample_rate: cpal::SampleRate(sample_rate),
         };
@@ -74,6 +140,36 @@ impl<'a> Source {
         Ok(stream)
     }
 
+    /// is_config_supported returns whether the device can capture with the given number of
+    /// channels, sample rate and fixed buffer size. If the host does not report a range of
+    /// buffer sizes, any buffer size is assumed to be supported. A loopback source is checked
+    /// against the output configs of its device.
+    pub fn is_config_supported(&self, channels: u16, sample_rate: u32, buffer_size: u32) -> bool {
+        let configs: Vec<cpal::SupportedStreamConfigRange> = if self.loopback {
+            match self.device.supported_output_configs() {
+                Ok(configs) => configs.collect(),
+                Err(_) => return false,
+            }
+        } else {
+            match self.device.supported_input_configs() {
+                Ok(configs) => configs.collect(),
+                Err(_) => return false,
+            }
+        };
+        configs.into_iter().any(|c| {
+            let buffer_size_ok = match c.buffer_size() {
+                cpal::SupportedBufferSize::Range { min, max } => {
+                    *min <= buffer_size && buffer_size <= *max
+                }
+                cpal::SupportedBufferSize::Unknown => true,
+            };
+            c.channels() == channels
+                && c.min_sample_rate().0 <= sample_rate
+                && sample_rate <= c.max_sample_rate().0
+                && buffer_size_ok
+        })
+    }
+
     pub fn list_devices() -> Vec<(cpal::HostId, cpal::InputDevices<cpal::Devices>)> {
         cpal::available_hosts()
             .iter()
@@ -88,16 +184,28 @@ impl<'a> Source {
             .collect()
     }
 
+    /// default_device_name returns the name of the device selected by `Source::new(None)`.
+    pub fn default_device_name() -> Option<String> {
+        cpal::default_host()
+            .default_input_device()
+            .and_then(|d| d.name().ok())
+    }
+
     pub fn print_devices(show_supported_configs: bool) -> Result<()> {
         let hosts = Self::list_devices();
+        let default_host = cpal::default_host().id();
+        let default_name = Self::default_device_name();
         for (host, devices) in hosts {
             for dev in devices {
-                println!(
-                    "({:?}) Audio Device:\t{:#?}",
-                    host,
-                    dev.name()
-                        .map_err(|e| anyhow!("error getting name: {}", e))?,
-                );
+                let name = dev
+                    .name()
+                    .map_err(|e| anyhow!("error getting name: {}", e))?;
+                let marker = if host == default_host && Some(&name) == default_name.as_ref() {
+                    " (default)"
+                } else {
+                    ""
+                };
+                println!("({:?}) Audio Device:\t{:#?}{}", host, name, marker);
                 if show_supported_configs {
                     let configs = dev
                         .supported_input_configs()
@@ -113,7 +221,8 @@ impl<'a> Source {
 
 #[cfg(test)]
 mod tests {
-    use super::Source;
+    use super::{i16_block_stats, Source};
+    use cpal::traits::DeviceTrait;
     use std::sync::{Arc, Mutex};
 
     #[test]
@@ -152,4 +261,57 @@ mod tests {
         // own wrapper to implement a non-blocking interface.
         assert_eq!(res.len(), 44100);
     }
+
+    #[test]
+    fn is_config_supported() {
+        // skipped when there is no usable input device
+        let s = match Source::new(None) {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+        let config = match s.device.default_input_config() {
+            Ok(config) => config,
+            Err(_) => return,
+        };
+        let buffer_size = match config.buffer_size() {
+            cpal::SupportedBufferSize::Range { min, .. } => *min,
+            cpal::SupportedBufferSize::Unknown => 256,
+        };
+
+        assert!(s.is_config_supported(config.channels(), config.sample_rate().0, buffer_size));
+        assert!(!s.is_config_supported(config.channels(), 1_000_000_000, buffer_size));
+    }
+
+    #[test]
+    fn default_device_name() {
+        // skipped when there is no usable input device
+        let s = match Source::new(None) {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+        assert_eq!(Source::default_device_name(), s.device.name().ok());
+    }
+
+    #[test]
+    fn i16_stats() {
+        let (rms, peak) = i16_block_stats(&[16384, -16384, 16384, -16384]);
+        assert_eq!(rms, 0.5);
+        assert_eq!(peak, 16384);
+
+        let (rms, peak) = i16_block_stats(&[0, 3, -4, 0]);
+        assert!((rms - 2.5 / 32768.).abs() < 1e-12);
+        assert_eq!(peak, 4);
+
+        assert_eq!(i16_block_stats(&[i16::MIN]).1, i16::MAX);
+        assert_eq!(i16_block_stats(&[]), (0., 0));
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn output_loopback_unsupported() {
+        let err = Source::output_loopback(None)
+            .err()
+            .expect("loopback should be unsupported");
+        assert!(err.to_string().contains("monitor source"));
+    }
 }
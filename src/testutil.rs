@@ -0,0 +1,117 @@
+//! Helpers for characterizing how a set of params responds to input.
+
+use anyhow::{anyhow, Result};
+
+use crate::analyzer::{Analyzer, AnalyzerParams};
+use crate::types::Frame;
+
+// the number of blocks without features after which impulse_response gives up
+const MAX_BLOCKS_PER_EMIT: usize = 1024;
+
+/// impulse_response feeds silence through the analyzer until it emits features, then a frame
+/// of `block_size` samples with a unit impulse at its center, followed by more silence. It
+/// returns the total energy, the sum of squared amplitudes, of each of the next `frames`
+/// features emitted, which shows how long a spike persists for the given params. An error is
+/// returned if the analyzer goes `MAX_BLOCKS_PER_EMIT` blocks without emitting features.
+pub fn impulse_response(
+    analyzer: &mut Analyzer,
+    params: &AnalyzerParams,
+    block_size: usize,
+    frames: usize,
+) -> Result<Vec<f64>> {
+    let mut frame = Frame::new(block_size);
+    let emit = |analyzer: &mut Analyzer, frame: &mut Frame| {
+        for _ in 0..MAX_BLOCKS_PER_EMIT {
+            if let Some(features) = analyzer.process(frame, params) {
+                return Ok(features);
+            }
+            for x in frame.iter_mut() {
+                *x = 0.;
+            }
+        }
+        Err(anyhow!(
+            "analyzer emitted no features in {} blocks",
+            MAX_BLOCKS_PER_EMIT
+        ))
+    };
+    emit(analyzer, &mut frame)?;
+
+    frame[block_size / 2] = 1.;
+    let mut response = Vec::with_capacity(frames);
+    while response.len() < frames {
+        let features = emit(analyzer, &mut frame)?;
+        response.push(features.get_amplitudes(0).iter().map(|x| x * x).sum());
+        for x in frame.iter_mut() {
+            *x = 0.;
+        }
+    }
+    Ok(response)
+}
+
+// alloc counts heap allocations for tests of the real-time paths
+#[cfg(test)]
+pub(crate) mod alloc {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    thread_local! {
+        static COUNT: Cell<usize> = const { Cell::new(0) };
+    }
+
+    struct Counting;
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = COUNT.try_with(|c| c.set(c.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    /// count_allocations returns the number of heap allocations `f` makes on this thread.
+    pub fn count_allocations<F: FnOnce()>(f: F) -> usize {
+        let before = COUNT.with(|c| c.get());
+        f();
+        COUNT.with(|c| c.get()) - before
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::impulse_response;
+    use crate::analyzer::{Analyzer, AnalyzerParams};
+
+    #[test]
+    fn decays_after_impulse() {
+        let mut a = Analyzer::new(256, 64, 16, 2);
+        let response = impulse_response(&mut a, &Default::default(), 64, 80).unwrap();
+
+        let peak = (0..response.len())
+            .max_by(|&i, &j| response[i].partial_cmp(&response[j]).unwrap())
+            .unwrap();
+        assert!(response[peak] > 0.);
+        for w in response[..=peak].windows(2) {
+            assert!(w[1] > w[0], "{:?}", response);
+        }
+        for w in response[peak..].windows(2) {
+            assert!(w[1] < w[0], "{:?}", response);
+        }
+        assert!(response[response.len() - 1] < 0.01 * response[peak]);
+    }
+
+    #[test]
+    fn gives_up_without_features() {
+        let params = AnalyzerParams {
+            min_emit_interval_samples: usize::MAX,
+            ..Default::default()
+        };
+        let mut a = Analyzer::new(256, 64, 16, 2);
+        assert!(impulse_response(&mut a, &params, 64, 4).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use std::ops::{Deref, DerefMut};
+
+macro_rules! vec_newtype {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, Default, PartialEq)]
+        pub struct $name(pub Vec<f64>);
+
+        impl $name {
+            pub fn new(size: usize) -> Self {
+                $name(vec![0f64; size])
+            }
+
+            pub fn into_inner(self) -> Vec<f64> {
+                self.0
+            }
+        }
+
+        impl Deref for $name {
+            type Target = Vec<f64>;
+
+            fn deref(&self) -> &Vec<f64> {
+                &self.0
+            }
+        }
+
+        impl DerefMut for $name {
+            fn deref_mut(&mut self) -> &mut Vec<f64> {
+                &mut self.0
+            }
+        }
+
+        impl From<Vec<f64>> for $name {
+            fn from(v: Vec<f64>) -> Self {
+                $name(v)
+            }
+        }
+
+        impl std::iter::FromIterator<f64> for $name {
+            fn from_iter<I: IntoIterator<Item = f64>>(iter: I) -> Self {
+                $name(iter.into_iter().collect())
+            }
+        }
+
+        impl PartialEq<Vec<f64>> for $name {
+            fn eq(&self, other: &Vec<f64>) -> bool {
+                &self.0 == other
+            }
+        }
+    };
+}
+
+vec_newtype!(
+    /// Frame is a block of time-domain samples.
+    Frame
+);
+
+vec_newtype!(
+    /// Spectrum is the per-bin magnitude output of an FFT.
+    Spectrum
+);
+
+vec_newtype!(
+    /// Buckets is a spectrum which has been aggregated into frequency bins.
+    Buckets
+);
+
+#[cfg(test)]
+mod tests {
+    use super::{Buckets, Frame, Spectrum};
+    use crate::bucketer::Bucketer;
+    use crate::sfft::SlidingFFT;
+
+    #[test]
+    fn it_works() {
+        let mut f = Frame::from(vec![1., 2., 3.]);
+        assert_eq!(f.len(), 3);
+        assert_eq!(f[1], 2.);
+        f.push(4.);
+        assert_eq!(f, vec![1., 2., 3., 4.]);
+        assert_eq!(f.iter().sum::<f64>(), 10.);
+        assert_eq!(Spectrum::new(2).into_inner(), vec![0., 0.]);
+        assert_eq!(Buckets::default().len(), 0);
+    }
+
+    #[test]
+    fn pipeline_composes() {
+        let mut sfft = SlidingFFT::new(16);
+        let mut bucketer = Bucketer::new(sfft.output_size(), 4, 32., 16000.);
+
+        sfft.push_input(&Frame::from(vec![1.; 16]));
+        let spectrum: &Spectrum = sfft.process();
+        let buckets: &mut Buckets = bucketer.bucket(spectrum);
+        assert_eq!(buckets.len(), 4);
+    }
+}
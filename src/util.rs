@@ -1,5 +1,24 @@
 use std::fmt::{Display, Error, Formatter};
 
+/// lerp interpolates linearly from a, when t is 0, to b, when t is 1.
+pub fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a * (1. - t) + b * t
+}
+
+pub fn lerp_usize(a: usize, b: usize, t: f64) -> usize {
+    lerp(a as f64, b as f64, t).round() as usize
+}
+
+/// pick returns a for t below one half and b otherwise, for values which can't be
+/// interpolated.
+pub fn pick<T>(a: T, b: T, t: f64) -> T {
+    if t < 0.5 {
+        a
+    } else {
+        b
+    }
+}
+
 pub struct VecFmt<'a>(pub &'a Vec<f64>);
 
 impl<'a> VecFmt<'a> {